@@ -3,10 +3,13 @@
 in vec3 frag_position;
 in vec3 frag_normal;
 in vec2 uv;
+flat in uint layer;
+in float frag_ao;
+in vec2 frag_light;
 
 out vec4 color;
 
-layout(binding = 0) uniform sampler2D t_color;
+layout(binding = 0) uniform sampler2DArray t_blocks;
 
 
 const float PI = 3.1415926;
@@ -23,7 +26,7 @@ void main() {
     vec3 lightStrength = vec3(3, 3, 3);
 
     vec3 objColor = vec3(0.3, 0.5, 0.2);
-    objColor = texture(t_color, uv).rgb;
+    objColor = texture(t_blocks, vec3(uv, layer)).rgb;
 
     vec3 wi = normalize(lightPos - frag_position);
     vec3 V = normalize(cameraPos - frag_position);
@@ -37,11 +40,17 @@ void main() {
     float cos_theta = max(dot(N, wi), 0.0);
 
     float attenuation = 1.0 / square(distance);
-    vec3 ambient = vec3(0.01, 0.01, 0.01);
+    // Sky and block light levels, every level is 80% as bright as the one above
+    float sky = pow(0.8, 15.0 * (1.0 - frag_light.x));
+    float block = pow(0.8, 15.0 * (1.0 - frag_light.y));
+    vec3 ambient = max(vec3(sky), block * vec3(1.0, 0.85, 0.6));
 
     vec3 radiance = lightStrength * attenuation * cos_theta + ambient;
 
-    vec3 diffuse = radiance * objColor;
+    // Baked corner occlusion, kept above zero so creases don't turn black
+    float occlusion = mix(0.35, 1.0, frag_ao);
+
+    vec3 diffuse = radiance * objColor * occlusion;
     vec3 specular = pow(NdotH, specularHardness) * radiance;
 
     color = vec4(diffuse + specular, 1.0);
@@ -3,24 +3,31 @@ layout (triangles) in;
 layout (triangle_strip, max_vertices=3) out;
 
 in vec3 frag_pos[];
+flat in uint vert_layer[];
+in float vert_ao[];
+in vec2 vert_light[];
 
 out vec3 frag_position;
 out vec3 frag_normal;
 out vec2 uv;
+flat out uint layer;
+out float frag_ao;
+out vec2 frag_light;
 
 vec2 get_uv(vec3 N, vec3 pos) {
 	const vec3 UP = vec3(0.0, 1.0, 0.0);
 	const vec3 RIGHT = vec3(1.0, 0.0, 0.0);
 
 
+	// Side textures are flipped vertically, so their top row is at the top of the voxel
 	if (abs(dot(N, UP)) > 0.9) {
 		return pos.xz;
 	}
 	else if (abs(dot(N, RIGHT)) > 0.9) {
-		return pos.yz;
+		return vec2(pos.z, -pos.y);
 	}
 	else {
-		return pos.xy;
+		return vec2(pos.x, -pos.y);
 	}
 }
 
@@ -28,28 +35,38 @@ void main() {
 	vec3 U = frag_pos[1] - frag_pos[0];
 	vec3 V = frag_pos[2] - frag_pos[0];
 
-	vec3 N = vec3(
+	// Greedy quads span several voxels, so the cross product is not unit length
+	vec3 N = normalize(vec3(
 		(U.y * V.z) - (U.z * V.y),
 		(U.z * V.x) - (U.x * V.z),
 		(U.x * V.y) - (U.y * V.x)
-	);
+	));
 
 
     gl_Position = gl_in[0].gl_Position;
 	frag_normal = N;
 	uv = get_uv(N, frag_pos[0]);
 	frag_position = frag_pos[0];
+	layer = vert_layer[0];
+	frag_ao = vert_ao[0];
+	frag_light = vert_light[0];
     EmitVertex();
 
     gl_Position = gl_in[1].gl_Position;
 	frag_normal = N;
 	uv = get_uv(N, frag_pos[1]);
 	frag_position = frag_pos[1];
+	layer = vert_layer[1];
+	frag_ao = vert_ao[1];
+	frag_light = vert_light[1];
     EmitVertex();
 
 	gl_Position = gl_in[2].gl_Position;
 	uv = get_uv(N, frag_pos[2]);
 	frag_position = frag_pos[2];
+	layer = vert_layer[2];
+	frag_ao = vert_ao[2];
+	frag_light = vert_light[2];
 	frag_normal = N;
 
 	EmitVertex();
@@ -1,19 +1,27 @@
 #version 410 core
 
 layout(location = 0) in vec3 position;
+layout(location = 1) in uint layer;
+layout(location = 2) in float ao;
+layout(location = 3) in vec2 light;
 
 out vec3 frag_pos;
+flat out uint vert_layer;
+out float vert_ao;
+out vec2 vert_light;
 
 uniform mat4 projection;
 uniform mat4 view;
 
-uniform ivec2 chunkDims;
-uniform ivec2 chunkIndex;
+uniform int chunkDim;
+uniform ivec3 chunkIndex;
 
 void main() {
-	vec2 chunk_origin_xz = chunkIndex * chunkDims;
-	vec3 chunk_origin = vec3(chunk_origin_xz.x, 0, chunk_origin_xz.y);
+	vec3 chunk_origin = vec3(chunkIndex * chunkDim);
 
 	gl_Position = projection * view * vec4(chunk_origin + position, 1.0);
 	frag_pos = position;
+	vert_layer = layer;
+	vert_ao = ao;
+	vert_light = light;
 }
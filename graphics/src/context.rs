@@ -25,6 +25,7 @@ impl OpenGLContext {
             )
             .expect("failed to create glfw window");
         window.set_key_polling(true);
+        window.set_mouse_button_polling(true);
         window.make_current();
 
         unsafe {
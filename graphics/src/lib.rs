@@ -5,7 +5,7 @@ extern crate cgmath;
 pub use self::context::OpenGLContext;
 pub use self::mesh::Mesh;
 pub use self::shader::Shader;
-pub use self::texture::Texture2D;
+pub use self::texture::{Texture2D, Texture2DArray};
 
 pub mod context;
 pub mod mesh;
@@ -59,6 +59,16 @@ impl Uniform for Vector2<i32> {
     }
 }
 
+impl Uniform for Vector3<i32> {
+    fn set(&self, id: &str, handle: GLuint) {
+        unsafe {
+            let name = CString::new(id.as_bytes()).unwrap();
+            let location = gl::GetUniformLocation(handle, name.as_ptr());
+            gl::ProgramUniform3iv(handle, location, 1, ::std::mem::transmute(self));
+        }
+    }
+}
+
 impl Uniform for i32 {
     fn set(&self, id: &str, handle: GLuint) {
         unsafe {
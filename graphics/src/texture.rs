@@ -93,3 +93,127 @@ impl Texture2D {
         }
     }
 }
+
+/// Several images stored as the layers of one `GL_TEXTURE_2D_ARRAY`, so
+/// geometry using different textures can be drawn in a single call.
+///
+/// All layers share one size: images smaller than the largest one are scaled
+/// up with nearest filtering, which keeps pixel art crisp.
+pub struct Texture2DArray {
+    pub width: usize,
+    pub height: usize,
+    pub layers: usize,
+    pub format: GLenum,
+    pub handle: GLuint,
+}
+
+impl Debug for Texture2DArray {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Texture2DArray {{ {}x{}x{}, format: {}, handle: {} }}",
+            self.width, self.height, self.layers, self.format, self.handle
+        )
+    }
+}
+
+impl Texture2DArray {
+    pub fn new(paths: &[PathBuf], format: GLenum) -> Texture2DArray {
+        if paths.is_empty() {
+            panic!("Texture array needs at least one image.");
+        }
+
+        let images: Vec<Image<u8>> = paths
+            .iter()
+            .map(|path| match stb_image::image::load(path) {
+                LoadResult::ImageU8(data) => data,
+                LoadResult::ImageF32(..) => {
+                    panic!("Found floating point texture {}.", path.display())
+                }
+                LoadResult::Error(string) => panic!("{}: {}", path.display(), string),
+            })
+            .collect();
+
+        let width = images.iter().map(|image| image.width).max().unwrap();
+        let height = images.iter().map(|image| image.height).max().unwrap();
+
+        let mut handle = 0;
+        unsafe { gl::CreateTextures(gl::TEXTURE_2D_ARRAY, 1, &mut handle) };
+
+        let obj = Texture2DArray {
+            width,
+            height,
+            layers: images.len(),
+            format,
+            handle,
+        };
+        let pixel_formats = [0, gl::RED, gl::RG, gl::RGB, gl::RGBA];
+
+        unsafe {
+            gl::TextureStorage3D(
+                obj.handle,
+                obj.get_mipmap_levels(),
+                obj.format,
+                width as i32,
+                height as i32,
+                images.len() as i32,
+            );
+
+            for (layer, image) in images.iter().enumerate() {
+                let data = scale_nearest(image, width, height);
+                gl::TextureSubImage3D(
+                    obj.handle,
+                    0,
+                    0,
+                    0,
+                    layer as i32,
+                    width as i32,
+                    height as i32,
+                    1,
+                    pixel_formats[image.depth],
+                    gl::UNSIGNED_BYTE,
+                    data.as_ptr() as *const GLvoid,
+                );
+            }
+
+            gl::TextureParameteri(handle, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
+            gl::TextureParameteri(handle, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
+            gl::TextureParameteri(
+                handle,
+                gl::TEXTURE_MIN_FILTER,
+                gl::NEAREST_MIPMAP_LINEAR as i32,
+            );
+            gl::TextureParameteri(handle, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+            gl::GenerateTextureMipmap(obj.handle);
+        }
+        obj
+    }
+
+    fn get_mipmap_levels(&self) -> i32 {
+        1 + (self.width.max(self.height) as f32).log2().floor() as i32
+    }
+
+    pub fn bind(&self, texture_unit: u32) {
+        unsafe {
+            gl::ActiveTexture(gl::TEXTURE0 + texture_unit);
+            gl::BindTexture(gl::TEXTURE_2D_ARRAY, self.handle);
+        }
+    }
+}
+
+fn scale_nearest(image: &Image<u8>, width: usize, height: usize) -> Vec<u8> {
+    if image.width == width && image.height == height {
+        return image.data.clone();
+    }
+
+    let mut data = Vec::with_capacity(width * height * image.depth);
+    for y in 0..height {
+        let src_y = y * image.height / height;
+        for x in 0..width {
+            let src_x = x * image.width / width;
+            let start = (src_y * image.width + src_x) * image.depth;
+            data.extend_from_slice(&image.data[start..start + image.depth]);
+        }
+    }
+    data
+}
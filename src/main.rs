@@ -7,7 +7,7 @@ mod transform;
 
 use camera::Camera;
 use glfw::{Action, Context, Key};
-use graphics::{Mesh, OpenGLContext, Shader, Texture2D};
+use graphics::{Mesh, OpenGLContext, Shader, Texture2D, Texture2DArray};
 use rectangle_shape::RectangleShape;
 use transform::Transform;
 
@@ -55,7 +55,95 @@ fn load_shader(path: PathBuf) -> Shader {
     return shader;
 }
 
+/// How far away voxels can be broken or placed.
+const REACH: f32 = 8.0;
+
+/// Where the world is loaded from at startup and saved to on exit, unless
+/// `--save` picks another directory.
+const SAVE_DIR: &str = "saves/world";
+
+/// Printed along with mistakes on the command line.
+const USAGE: &str = "Usage: rs-tri [--save DIR] [SEED] [--superflat LAYERS]";
+
+/// Keys selecting the voxel type to place, in the order of `placeable`.
+const NUMBER_KEYS: [Key; 9] = [
+    Key::Num1,
+    Key::Num2,
+    Key::Num3,
+    Key::Num4,
+    Key::Num5,
+    Key::Num6,
+    Key::Num7,
+    Key::Num8,
+    Key::Num9,
+];
+
+/// Voxels taken up by the player. Collisions are tested one unit below the
+/// camera, so the player spans the voxel of the camera and the one below it.
+fn player_voxels(world: &World, camera: &Camera) -> [VoxelIndex; 2] {
+    let head = camera.transform.position;
+    let feet = head - Vector3::new(0.0, 1.0, 0.0);
+    [world.voxel_from_world(head), world.voxel_from_world(feet)]
+}
+
+/// What the command line asks for. The seed and the superflat layers only
+/// apply to a new world.
+#[derive(Default)]
+struct Options {
+    save_dir: Option<String>,
+    seed: Option<u64>,
+    superflat: Option<String>,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--save" => options.save_dir = Some(args.next().ok_or("--save needs a directory")?),
+            "--superflat" => {
+                options.superflat = Some(args.next().ok_or("--superflat needs a layer list")?)
+            }
+            _ => match arg.parse() {
+                Ok(seed) if options.seed.is_none() => options.seed = Some(seed),
+                _ => return Err(format!("Unexpected argument {}", arg)),
+            },
+        }
+    }
+    Ok(options)
+}
+
+fn usage_error(error: &str) -> ! {
+    eprintln!("{}\n{}", error, USAGE);
+    std::process::exit(2)
+}
+
 fn main() {
+    let blocks =
+        registry::BlockRegistry::load("assets/blocks.json").expect("Failed to load block set.");
+    registry::install(blocks).expect("Block registry was used before it was installed.");
+    // Generation stages of our own are registered here, before any preset is
+    // loaded
+    stages::install(stages::StageRegistry::builtin())
+        .expect("Stage registry was used before it was installed.");
+
+    // Mistakes on the command line are caught before the window opens
+    let Options {
+        save_dir,
+        seed,
+        superflat,
+    } = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| usage_error(&e));
+    let save_dir = save_dir.unwrap_or_else(|| SAVE_DIR.to_string());
+    let superflat = superflat.map(|layers| {
+        TerrainPreset::superflat(&layers)
+            .unwrap_or_else(|e| usage_error(&format!("Invalid superflat layers: {}", e)))
+    });
+    if World::exists(&save_dir) && (seed.is_some() || superflat.is_some()) {
+        usage_error(&format!(
+            "{} already has a world, a seed or --superflat only apply to new ones",
+            save_dir
+        ));
+    }
+
     let mut opengl = OpenGLContext::new();
     let r = RectangleShape::new(1280.0, 720.0);
     println!("{:?}", r);
@@ -166,7 +254,12 @@ fn main() {
     let mesh = Mesh::load_ply(PathBuf::from("assets/meshes/cube.ply"));
     println!("{:?}", mesh);
 
-    let dirt = Texture2D::new(PathBuf::from("assets/textures/dirt.jpg"), gl::SRGB8);
+    let block_textures: Vec<PathBuf> = registry::get()
+        .textures()
+        .iter()
+        .map(|texture| PathBuf::from("assets/textures").join(texture))
+        .collect();
+    let block_textures = Texture2DArray::new(&block_textures, gl::SRGB8_ALPHA8);
 
     let albedo = Texture2D::new(
         PathBuf::from("assets/textures/harshbricks-albedo.png"),
@@ -191,14 +284,39 @@ fn main() {
         },
     );
     camera.transform.position.z = -3.0;
-    camera.transform.position.y = chunk::CHUNK_HEIGHT as f32 - 4.0;
-    let mut world = World::empty();
-
-    for x in (-10)..10 {
-        for z in (-10)..10 {
-            world.gen_chunk((x, z));
+    camera.transform.position.y = 90.0;
+    // A new world takes its seed from the command line, or the clock. It's
+    // superflat with `--superflat "1 bedrock, 3 stone, 1 grass"`. Another one
+    // is started next to an existing save with `--save saves/other`.
+    let mut world = World::load(&save_dir).unwrap_or_else(|e| {
+        let seed = seed.unwrap_or_else(|| {
+            time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .map_or(0, |t| t.as_secs())
+        });
+        println!("Starting a new world with seed {}: {}", seed, e);
+        let preset = match superflat {
+            Some(preset) => preset,
+            None => {
+                TerrainPreset::load("assets/terrain.json").expect("Failed to load terrain preset.")
+            }
+        };
+        // Saved right away, so edited chunks streaming out before the first
+        // `save` are kept too
+        let mut world = World::new(seed, preset);
+        if let Err(e) = world.save(&save_dir) {
+            println!("Edits won't be saved: {}", e);
         }
-    }
+        world
+    });
+    let streaming = StreamingConfig::default();
+
+    let placeable: Vec<VoxelType> = registry::get()
+        .blocks()
+        .filter(|block| block.solid)
+        .map(|block| block.id)
+        .collect();
+    let mut selected_voxel = placeable[0];
 
     let command_buffer = Arc::new(Mutex::new(Vec::new()));
     {
@@ -239,6 +357,54 @@ fn main() {
                         print!("Shutting down...");
                         opengl.window.set_should_close(true);
                     }
+                    "save\r\n" => match world.save(&save_dir) {
+                        Ok(()) => println!("Saved to {}", save_dir),
+                        Err(e) => println!("{}", e),
+                    },
+                    "seed\r\n" => println!("{}", world.seed()),
+                    "stages\r\n" => {
+                        for timing in world.stage_timings() {
+                            println!(
+                                "{}: {} chunks, {:?} per chunk",
+                                timing.name,
+                                timing.runs,
+                                timing.total / timing.runs.max(1) as u32
+                            );
+                        }
+                    }
+                    "biome\r\n" => {
+                        let voxel = world.voxel_from_world(camera.transform.position);
+                        println!("{}", world.biome_at(voxel).name);
+                    }
+                    "memory\r\n" => {
+                        let (chunks, bytes) = world.chunk_memory();
+                        println!(
+                            "{} chunks, {} KiB ({} bytes per chunk)",
+                            chunks,
+                            bytes / 1024,
+                            bytes / chunks.max(1)
+                        );
+                    }
+                    ores if ores.split_whitespace().next() == Some("ores") => {
+                        let radius = match ores.split_whitespace().nth(1).map(str::parse) {
+                            None => 2,
+                            Some(Ok(radius)) if radius >= 0 => radius,
+                            Some(_) => {
+                                println!("Usage: ores [radius in chunks]");
+                                continue;
+                            }
+                        };
+                        let center = world
+                            .voxel_from_world(camera.transform.position)
+                            .chunk_index();
+                        let (chunks, counts) = world.ore_counts(center, radius);
+                        println!("{} chunks within {} of {:?}", chunks, radius, center);
+                        for (ore, count) in counts {
+                            if let Some(def) = registry::get().get(ore) {
+                                println!("{}: {}", def.name, count);
+                            }
+                        }
+                    }
                     _ => {
                         println!("Unknown command: {}", command);
                     }
@@ -255,6 +421,32 @@ fn main() {
                 glfw::WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
                     opengl.window.set_should_close(true);
                 }
+                glfw::WindowEvent::Key(key, _, Action::Press, _) if NUMBER_KEYS.contains(&key) => {
+                    let n = NUMBER_KEYS.iter().position(|k| *k == key).unwrap();
+                    if let Some(voxel_type) = placeable.get(n) {
+                        selected_voxel = *voxel_type;
+                    }
+                }
+                glfw::WindowEvent::MouseButton(glfw::MouseButton::Button1, Action::Press, _) => {
+                    let hit =
+                        world.raycast(camera.transform.position, camera.transform.forward(), REACH);
+                    if let Some(hit) = hit {
+                        world.set_voxel(hit.voxel, Voxel::void());
+                        world.remesh_edited(hit.voxel);
+                    }
+                }
+                glfw::WindowEvent::MouseButton(glfw::MouseButton::Button2, Action::Press, _) => {
+                    let target = world
+                        .raycast(camera.transform.position, camera.transform.forward(), REACH)
+                        .and_then(|hit| hit.previous);
+
+                    if let Some(target) = target {
+                        if !player_voxels(&world, &camera).contains(&target) {
+                            world.set_voxel(target, Voxel::new(selected_voxel));
+                            world.remesh_edited(target);
+                        }
+                    }
+                }
                 _ => {}
             }
         }
@@ -335,7 +527,14 @@ fn main() {
             // Quaternion::from_angle_x(Deg(-15.0 * delta_time)) * camera.transform.rotation;
         }
 
+        let player_chunk = world
+            .voxel_from_world(camera.transform.position)
+            .chunk_index();
+        world.stream_around(player_chunk, &streaming);
+
         // physics
+        // Unloaded chunks are solid, so the player waits in place until the
+        // terrain below them has been generated.
 
         const gravity: f32 = -9.810;
 
@@ -347,7 +546,7 @@ fn main() {
             camera.transform.position.x += velocity.x * delta_time;
             let voxel_coords = world.voxel_from_world(camera.transform.position);
 
-            if world.voxel(voxel_coords).is_solid() {
+            if world.is_blocking(voxel_coords) {
                 camera.transform.position.x -= velocity.x * delta_time;
                 velocity.x = 0.0;
                 dbg!("Collision X");
@@ -359,7 +558,7 @@ fn main() {
             camera.transform.position.y += velocity.y * delta_time;
             let voxel_coords = world.voxel_from_world(camera.transform.position);
 
-            if world.voxel(voxel_coords).is_solid() {
+            if world.is_blocking(voxel_coords) {
                 camera.transform.position.y -= velocity.y * delta_time;
                 velocity.y = 0.0;
                 // dbg!("Collision Y");
@@ -371,7 +570,7 @@ fn main() {
             camera.transform.position.z += velocity.z * delta_time;
             let voxel_coords = world.voxel_from_world(camera.transform.position);
 
-            if world.voxel(voxel_coords).is_solid() {
+            if world.is_blocking(voxel_coords) {
                 camera.transform.position.z -= velocity.z * delta_time;
                 velocity.z = 0.0;
                 dbg!("Collision Z");
@@ -379,6 +578,7 @@ fn main() {
         }
         camera.transform.position.y += 1.0;
 
+        world.update();
         renderer.camera = camera.clone();
 
         shader.setUniform("view", camera.get_view_matrix());
@@ -413,7 +613,7 @@ fn main() {
 
             shader.setUniform("model", Matrix4::<f32>::identity());
 
-            dirt.bind(0);
+            block_textures.bind(0);
             world.render(&renderer);
 
             gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
@@ -434,4 +634,8 @@ fn main() {
 
         opengl.window.swap_buffers();
     }
+
+    if let Err(e) = world.save(&save_dir) {
+        println!("{}", e);
+    }
 }
@@ -0,0 +1,358 @@
+use noise::NoiseFn;
+
+use crate::world::registry::{self, BlockRegistry};
+use crate::world::structures::{StructureKind, StructureRule};
+use crate::world::terrain::{build, NoiseNode};
+use crate::world::VoxelType;
+
+/// How far apart, in climate space, two biomes can be and still blend their
+/// heights, unless the preset sets its own `climate.blend`.
+const DEFAULT_BLEND: f64 = 0.15;
+
+/// Voxels placed on top of the surface of some columns of a biome, like
+/// cacti in a desert.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decoration {
+    pub voxel_type: VoxelType,
+    /// Share of the columns that get one, between 0 and 1.
+    pub chance: f64,
+    /// Smallest and largest number of voxels stacked on the surface.
+    pub height: (i32, i32),
+}
+
+pub struct Biome {
+    pub name: String,
+    /// Climate the biome is at home in, each roughly between -1 and 1.
+    pub temperature: f64,
+    pub humidity: f64,
+    height: NoiseNode,
+    pub surface: VoxelType,
+    pub subsurface: VoxelType,
+    pub decorations: Vec<Decoration>,
+    pub structures: Vec<StructureRule>,
+}
+
+/// Picks biomes by the temperature and humidity at a position, each position
+/// belongs to the biome with the closest climate.
+///
+/// The terrain height is a mix of the heights of every biome whose climate is
+/// within `blend` of the closest one, weighted by how close it is. The weights
+/// change smoothly with the climate, so there are no cliffs at biome borders.
+pub struct BiomeMap {
+    temperature: NoiseNode,
+    humidity: NoiseNode,
+    blend: f64,
+    biomes: Vec<Biome>,
+}
+
+impl BiomeMap {
+    /// Builds the biomes of a terrain preset for a world seed. A preset
+    /// without `biomes` is one biome with the preset's `height` everywhere.
+    pub fn new(preset: &serde_json::Value, seed: u64) -> Result<BiomeMap, String> {
+        BiomeMap::with_blocks(preset, seed, registry::get())
+    }
+
+    /// Builds the biomes looking up their blocks in a registry other than the
+    /// installed one.
+    pub fn with_blocks(
+        preset: &serde_json::Value,
+        seed: u64,
+        blocks: &BlockRegistry,
+    ) -> Result<BiomeMap, String> {
+        let climate = &preset["climate"];
+        let climate_node = |key: &str| match &climate[key] {
+            serde_json::Value::Null => Ok(NoiseNode::Constant(noise::Constant::new(0.0))),
+            node => build(node, seed, &format!("climate.{}", key)),
+        };
+        let blend = match &climate["blend"] {
+            serde_json::Value::Null => DEFAULT_BLEND,
+            blend => blend
+                .as_f64()
+                .filter(|blend| *blend > 0.0)
+                .ok_or("climate.blend needs to be a positive number")?,
+        };
+
+        let biomes = match preset["biomes"].as_array() {
+            Some(biomes) => biomes
+                .iter()
+                .enumerate()
+                .map(|(n, biome)| parse_biome(biome, seed, blocks, &format!("biomes.{}", n)))
+                .collect::<Result<Vec<Biome>, String>>()?,
+            None => vec![Biome {
+                name: "default".to_string(),
+                temperature: 0.0,
+                humidity: 0.0,
+                height: build(&preset["height"], seed, "height")?,
+                surface: default_block(blocks, "grass"),
+                subsurface: default_block(blocks, "dirt"),
+                decorations: Vec::new(),
+                structures: Vec::new(),
+            }],
+        };
+        if biomes.is_empty() {
+            return Err("A terrain preset needs at least one biome".to_string());
+        }
+
+        Ok(BiomeMap {
+            temperature: climate_node("temperature")?,
+            humidity: climate_node("humidity")?,
+            blend,
+            biomes,
+        })
+    }
+
+    /// Temperature and humidity of a column.
+    pub fn climate(&self, x: i32, z: i32) -> (f64, f64) {
+        let point = [x as f64, z as f64];
+        (self.temperature.get(point), self.humidity.get(point))
+    }
+
+    fn distances(&self, x: i32, z: i32) -> Vec<f64> {
+        let (temperature, humidity) = self.climate(x, z);
+        self.biomes
+            .iter()
+            .map(|biome| {
+                let (dt, dh) = (biome.temperature - temperature, biome.humidity - humidity);
+                (dt * dt + dh * dh).sqrt()
+            })
+            .collect()
+    }
+
+    pub fn biome_at(&self, x: i32, z: i32) -> &Biome {
+        let distances = self.distances(x, z);
+        let closest = (0..self.biomes.len())
+            .min_by(|a, b| distances[*a].partial_cmp(&distances[*b]).unwrap())
+            .unwrap();
+        &self.biomes[closest]
+    }
+
+    /// Surface height of a column, blended between nearby biomes.
+    pub fn height_at(&self, x: i32, z: i32) -> f64 {
+        let distances = self.distances(x, z);
+        let closest = distances.iter().cloned().fold(f64::INFINITY, f64::min);
+
+        let mut blended = Vec::new();
+        for (biome, distance) in self.biomes.iter().zip(distances.iter()) {
+            let weight = (self.blend - (distance - closest)).max(0.0);
+            if weight > 0.0 {
+                blended.push((biome, weight * weight));
+            }
+        }
+
+        let point = [x as f64, z as f64];
+        if let [(biome, _)] = blended.as_slice() {
+            return biome.height.get(point);
+        }
+        let total: f64 = blended.iter().map(|(_, weight)| weight).sum();
+        blended
+            .iter()
+            .map(|(biome, weight)| biome.height.get(point) * weight)
+            .sum::<f64>()
+            / total
+    }
+}
+
+/// A block the generator uses without it being named in the preset, like the
+/// stone of the terrain and the logs of trees. It falls back to plain ground,
+/// so the builtin terrain still works with a smaller block set.
+pub(super) fn block(name: &str) -> VoxelType {
+    default_block(registry::get(), name)
+}
+
+fn default_block(blocks: &BlockRegistry, name: &str) -> VoxelType {
+    blocks.find(name).unwrap_or(VoxelType::GROUND)
+}
+
+fn parse_biome(
+    biome: &serde_json::Value,
+    seed: u64,
+    blocks: &BlockRegistry,
+    path: &str,
+) -> Result<Biome, String> {
+    let name = biome["name"]
+        .as_str()
+        .ok_or_else(|| format!("{} needs a name", path))?
+        .to_string();
+    let climate = |key: &str| {
+        biome[key]
+            .as_f64()
+            .ok_or_else(|| format!("Biome {} needs a {}", name, key))
+    };
+    // Blocks named in the preset have to exist, a typo would silently turn
+    // into ground otherwise
+    let find = |block: &str| {
+        blocks
+            .find(block)
+            .ok_or_else(|| format!("Unknown block {} in biome {}", block, name))
+    };
+    let block_named = |key: &str, default: &str| match &biome[key] {
+        serde_json::Value::Null => Ok(default_block(blocks, default)),
+        value => value
+            .as_str()
+            .ok_or_else(|| format!("{}.{} needs to be a block name", name, key))
+            .and_then(find),
+    };
+
+    let decorations = match &biome["decorations"] {
+        serde_json::Value::Null => Vec::new(),
+        decorations => decorations
+            .as_array()
+            .ok_or_else(|| format!("{}.decorations needs to be a list", name))?
+            .iter()
+            .map(|decoration| {
+                let voxel_type = decoration["block"]
+                    .as_str()
+                    .ok_or_else(|| format!("Decoration of {} needs a block", name))
+                    .and_then(find)?;
+                let chance = decoration["chance"].as_f64().unwrap_or(0.0).clamp(0.0, 1.0);
+                let height = match decoration["height"].as_array().map(|h| h.as_slice()) {
+                    None => (1, 1),
+                    Some([low, high]) => match (low.as_i64(), high.as_i64()) {
+                        (Some(low), Some(high)) if low >= 1 && high >= low => {
+                            (low as i32, high as i32)
+                        }
+                        _ => return Err(format!("Decoration of {} has an invalid height", name)),
+                    },
+                    Some(_) => return Err(format!("Decoration of {} has an invalid height", name)),
+                };
+                Ok(Decoration {
+                    voxel_type,
+                    chance,
+                    height,
+                })
+            })
+            .collect::<Result<Vec<Decoration>, String>>()?,
+    };
+
+    let structures = match &biome["structures"] {
+        serde_json::Value::Null => Vec::new(),
+        structures => structures
+            .as_array()
+            .ok_or_else(|| format!("{}.structures needs to be a list", name))?
+            .iter()
+            .map(|structure| {
+                let kind = structure["type"]
+                    .as_str()
+                    .and_then(StructureKind::parse)
+                    .ok_or_else(|| format!("Structure of {} has an unknown type", name))?;
+                let chance = structure["chance"].as_f64().unwrap_or(0.0).clamp(0.0, 1.0);
+                Ok(StructureRule { kind, chance })
+            })
+            .collect::<Result<Vec<StructureRule>, String>>()?,
+    };
+
+    Ok(Biome {
+        temperature: climate("temperature")?,
+        humidity: climate("humidity")?,
+        height: build(&biome["height"], seed, &format!("{}.height", path))?,
+        surface: block_named("surface", "grass")?,
+        subsurface: block_named("subsurface", "dirt")?,
+        decorations,
+        structures,
+        name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn biomes(json: &str) -> BiomeMap {
+        BiomeMap::new(&serde_json::from_str(json).unwrap(), 7).unwrap()
+    }
+
+    fn desert_blocks() -> BlockRegistry {
+        BlockRegistry::from_json(
+            r#"{ "blocks": [
+                { "id": 1, "name": "dirt" },
+                { "id": 5, "name": "sand" },
+                { "id": 7, "name": "cactus" }
+            ] }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    pub fn closest_climate_wins() {
+        let preset: serde_json::Value = serde_json::from_str(
+            r#"{
+                "climate": {
+                    "temperature": { "type": "constant", "value": 0.7 },
+                    "humidity": { "type": "constant", "value": -0.6 }
+                },
+                "biomes": [
+                    { "name": "plains", "temperature": 0, "humidity": 0,
+                      "height": { "type": "constant", "value": 40 } },
+                    { "name": "desert", "temperature": 0.8, "humidity": -0.8,
+                      "height": { "type": "constant", "value": 30 }, "surface": "sand",
+                      "decorations": [{ "block": "cactus", "chance": 0.02, "height": [1, 3] }] }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let map = BiomeMap::with_blocks(&preset, 7, &desert_blocks()).unwrap();
+
+        let desert = map.biome_at(123, -45);
+        assert_eq!(desert.name, "desert");
+        assert_eq!(desert.surface, VoxelType(5));
+        assert_eq!(
+            desert.decorations,
+            vec![Decoration {
+                voxel_type: VoxelType(7),
+                chance: 0.02,
+                height: (1, 3),
+            }]
+        );
+        // Far from plains in climate space, nothing to blend with
+        assert_eq!(map.height_at(123, -45), 30.0);
+
+        // Unless the registry defines them, named blocks are typos
+        assert!(BiomeMap::new(&preset, 7).is_err());
+        let mut typo = preset.clone();
+        typo["biomes"][1]["decorations"][0]["block"] = "catcus".into();
+        assert!(BiomeMap::with_blocks(&typo, 7, &desert_blocks()).is_err());
+    }
+
+    #[test]
+    pub fn heights_blend_at_biome_borders() {
+        let map = biomes(
+            r#"{
+                "climate": {
+                    "temperature": { "type": "perlin", "frequency": 0.001 }
+                },
+                "biomes": [
+                    { "name": "tundra", "temperature": -0.2, "humidity": 0,
+                      "height": { "type": "constant", "value": 20 } },
+                    { "name": "desert", "temperature": 0.2, "humidity": 0,
+                      "height": { "type": "constant", "value": 60 } }
+                ]
+            }"#,
+        );
+
+        let columns = 0..8000;
+        let names: Vec<&str> = columns
+            .clone()
+            .map(|x| map.biome_at(x, 0).name.as_str())
+            .collect();
+        assert!(names.contains(&"tundra") && names.contains(&"desert"));
+
+        let heights: Vec<f64> = columns.map(|x| map.height_at(x, 0)).collect();
+        assert!(heights.contains(&20.0) && heights.contains(&60.0));
+        for step in heights.windows(2) {
+            assert!((step[1] - step[0]).abs() < 2.0, "cliff between {:?}", step);
+        }
+    }
+
+    #[test]
+    pub fn presets_without_biomes_have_one() {
+        let map = biomes(r#"{ "height": { "type": "constant", "value": 12 } }"#);
+        assert_eq!(map.biomes.len(), 1);
+        assert_eq!(map.height_at(5, 5), 12.0);
+
+        let invalid = |json: &str| BiomeMap::new(&serde_json::from_str(json).unwrap(), 0).is_err();
+        assert!(invalid(r#"{ "biomes": [] }"#));
+        assert!(invalid(
+            r#"{ "biomes": [{ "name": "a", "temperature": 0, "height": { "type": "constant", "value": 1 } }] }"#
+        ));
+    }
+}
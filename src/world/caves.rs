@@ -0,0 +1,126 @@
+use noise::NoiseFn;
+
+use crate::world::terrain::{build, NoiseNode};
+
+/// How far from the height map overhangs reach, unless the preset sets its
+/// own `overhangs.range`.
+const DEFAULT_OVERHANG_RANGE: f64 = 8.0;
+
+/// Cave voxels are carved where the cave density is above this, unless the
+/// preset sets its own `caves.threshold`.
+const DEFAULT_THRESHOLD: f64 = 0.5;
+
+/// Turns the height map of the biomes into a density field sampled at every
+/// voxel, then carves caves out of it.
+///
+/// A voxel is solid where `height - y + overhangs` is positive. Adding the 3D
+/// `overhangs` noise bends the surface into cliffs and overhangs. It's only
+/// sampled within `range` voxels of the height map, which is as far as it can
+/// move the surface.
+///
+/// Caves are carved wherever the 3D `caves` density is above its
+/// `threshold`, surface included, so they open up to the sky. Nothing below
+/// `floor` is carved. Everything is sampled at world positions, so caves run
+/// on from one chunk into the next.
+pub struct Caves {
+    overhangs: Option<NoiseNode>,
+    range: f64,
+    density: Option<NoiseNode>,
+    threshold: f64,
+    floor: i32,
+}
+
+impl Caves {
+    /// Builds the `overhangs` and `caves` of a terrain preset for a world
+    /// seed. Both are optional, without them the terrain is the height map.
+    pub fn new(preset: &serde_json::Value, seed: u64) -> Result<Caves, String> {
+        let overhangs = &preset["overhangs"];
+        let caves = &preset["caves"];
+        let number = |section: &serde_json::Value, path: &str, default: f64| match section {
+            serde_json::Value::Null => Ok(default),
+            value => value
+                .as_f64()
+                .ok_or_else(|| format!("{} needs to be a number", path)),
+        };
+        let node = |section: &serde_json::Value, path: &str| match section {
+            serde_json::Value::Null => Ok(None),
+            node => build(node, seed, path).map(Some),
+        };
+
+        Ok(Caves {
+            overhangs: node(&overhangs["density"], "overhangs.density")?,
+            range: number(
+                &overhangs["range"],
+                "overhangs.range",
+                DEFAULT_OVERHANG_RANGE,
+            )?,
+            density: node(&caves["density"], "caves.density")?,
+            threshold: number(&caves["threshold"], "caves.threshold", DEFAULT_THRESHOLD)?,
+            floor: number(&caves["floor"], "caves.floor", f64::from(i32::MIN))? as i32,
+        })
+    }
+
+    /// Whether a voxel is part of the ground before caves are carved, for a
+    /// column with the surface at `height`.
+    pub fn is_ground(&self, x: i32, y: i32, z: i32, height: f64) -> bool {
+        let depth = height - y as f64;
+        match &self.overhangs {
+            Some(overhangs) if depth.abs() < self.range => {
+                depth + overhangs.get([x as f64, y as f64, z as f64]) > 0.0
+            }
+            _ => depth > 0.0,
+        }
+    }
+
+    /// Whether a voxel of the ground is carved out by a cave.
+    pub fn is_carved(&self, x: i32, y: i32, z: i32) -> bool {
+        match &self.density {
+            Some(density) if y >= self.floor => {
+                density.get([x as f64, y as f64, z as f64]) > self.threshold
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caves(json: &str) -> Caves {
+        Caves::new(&serde_json::from_str(json).unwrap(), 3).unwrap()
+    }
+
+    #[test]
+    pub fn caves_stop_at_the_floor() {
+        let caves = caves(
+            r#"{ "caves": {
+                "density": { "type": "constant", "value": 1 },
+                "floor": -10
+            } }"#,
+        );
+        // Openings reach up through the surface
+        assert!(caves.is_carved(0, 40, 0));
+        assert!(caves.is_carved(5, -10, 5));
+        assert!(!caves.is_carved(5, -11, 5));
+    }
+
+    #[test]
+    pub fn overhangs_move_the_surface_near_the_height_map() {
+        let caves = caves(
+            r#"{ "overhangs": {
+                "density": { "type": "constant", "value": 3 },
+                "range": 5
+            } }"#,
+        );
+        assert!(caves.is_ground(0, 12, 0, 10.0));
+        assert!(!caves.is_ground(0, 13, 0, 10.0));
+        // Out of range the height map is left as it is
+        assert!(!caves.is_ground(0, 10, 0, 4.0));
+
+        let plain = Caves::new(&serde_json::json!({}), 0).unwrap();
+        assert!(plain.is_ground(0, 9, 0, 9.5));
+        assert!(!plain.is_ground(0, 10, 0, 9.5));
+        assert!(!plain.is_carved(0, 0, 0));
+    }
+}
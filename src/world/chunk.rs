@@ -1,37 +1,71 @@
+use crate::world::light::{ChunkLight, Light};
+use crate::world::palette::PalettedVoxels;
 use crate::world::{Voxel, VoxelType};
 
-use cgmath::Vector3;
-
-use gl::types::*;
 use std::mem;
-use std::ptr;
-
-struct BasicVertex {
-    position: Vector3<f32>,
+use std::ops::{Deref, DerefMut};
+
+/// Edge length of the cubic chunk sections the world is made of.
+pub const CHUNK_DIM: i32 = 16;
+pub const CHUNK_N_VOXELS: usize = (CHUNK_DIM * CHUNK_DIM * CHUNK_DIM) as usize;
+
+/// Offsets of the neighbours sharing a face with a chunk, in the order of
+/// `registry::FACES`: -x, +x, -y, +y, -z, +z.
+pub const SIDES: [(i32, i32, i32); 6] = [
+    (-1, 0, 0),
+    (1, 0, 0),
+    (0, -1, 0),
+    (0, 1, 0),
+    (0, 0, -1),
+    (0, 0, 1),
+];
+
+/// Length of arrays holding something for a chunk and each of the 26 chunks
+/// around it, see `neighbour_slot`.
+pub const NEIGHBOURHOOD: usize = 27;
+
+/// Position of the neighbour at an offset of -1 to 1 on every axis in an array
+/// of length `NEIGHBOURHOOD`. The chunk itself is at `neighbour_slot(0, 0, 0)`.
+pub fn neighbour_slot(x: i32, y: i32, z: i32) -> usize {
+    ((x + 1) + (y + 1) * 3 + (z + 1) * 9) as usize
 }
 
-impl BasicVertex {
-    pub fn from_pos(x: f32, y: f32, z: f32) -> BasicVertex {
-        BasicVertex {
-            position: Vector3::new(x, y, z),
-        }
-    }
+/// Offsets of the 26 chunks sharing a face, edge or corner with a chunk.
+pub fn neighbour_offsets() -> impl Iterator<Item = (i32, i32, i32)> {
+    (0..NEIGHBOURHOOD as i32)
+        .map(|n| (n % 3 - 1, n / 3 % 3 - 1, n / 9 - 1))
+        .filter(|offset| *offset != (0, 0, 0))
 }
 
-pub const CHUNK_DIM: i32 = 8;
-pub const CHUNK_HEIGHT: i32 = 124;
-pub const CHUNK_N_VOXELS: usize = (CHUNK_DIM * CHUNK_DIM * CHUNK_HEIGHT) as usize;
+fn voxel_offset(x: i32, y: i32, z: i32) -> usize {
+    (z + y * CHUNK_DIM * CHUNK_DIM + x * CHUNK_DIM) as usize
+}
 
+#[derive(Clone)]
 pub struct Chunk {
-    pub voxels: [Voxel; CHUNK_N_VOXELS],
+    pub voxels: PalettedVoxels,
+    /// Filled in by the `light::LightEngine` once the chunk is in the world.
+    pub light: ChunkLight,
     pub dirty: bool,
+    /// Edited since it was generated, so it has to be saved.
+    pub modified: bool,
+    /// Neighbours (by `neighbour_slot`) touching edited border voxels, they
+    /// need to be re-meshed as well.
+    pub edited_neighbours: [bool; NEIGHBOURHOOD],
+    /// Neighbours (by `neighbour_slot`) whose structures were placed into the
+    /// chunk. They're saved with it, so they aren't placed a second time.
+    pub received: [bool; NEIGHBOURHOOD],
 }
 
 impl Chunk {
     pub fn void() -> Chunk {
         Chunk {
-            voxels: [Voxel::void(); CHUNK_N_VOXELS],
+            voxels: PalettedVoxels::uniform(CHUNK_N_VOXELS, Voxel::void()),
+            light: ChunkLight::Uniform(Light::default()),
             dirty: false,
+            modified: false,
+            edited_neighbours: [false; NEIGHBOURHOOD],
+            received: [false; NEIGHBOURHOOD],
         }
     }
 
@@ -39,15 +73,19 @@ impl Chunk {
     where
         F: FnMut((i32, i32, i32), &mut Voxel),
     {
+        // Packing the whole chunk at once picks the smallest palette right away
+        let mut voxels = self.voxels.to_vec();
         for z in 0..CHUNK_DIM {
-            for y in 0..CHUNK_HEIGHT {
+            for y in 0..CHUNK_DIM {
                 for x in 0..CHUNK_DIM {
-                    f((x, y, z), self.voxel_mut(x, y, z))
+                    f((x, y, z), &mut voxels[voxel_offset(x, y, z)])
                 }
             }
         }
+        self.voxels = PalettedVoxels::from_voxels(&voxels);
 
         self.dirty = true;
+        self.edited_neighbours = [true; NEIGHBOURHOOD];
     }
 
     pub fn iter<F>(&self, f: F)
@@ -55,7 +93,7 @@ impl Chunk {
         F: Fn((i32, i32, i32), &Voxel),
     {
         for z in 0..CHUNK_DIM {
-            for y in 0..CHUNK_HEIGHT {
+            for y in 0..CHUNK_DIM {
                 for x in 0..CHUNK_DIM {
                     f((x, y, z), self.voxel(x, y, z))
                 }
@@ -63,172 +101,111 @@ impl Chunk {
         }
     }
 
-    pub fn voxel_mut(&mut self, x: i32, y: i32, z: i32) -> &mut Voxel {
+    // Faces of the neighbours are meshed against the voxels and light along
+    // the border, so they need to be re-meshed as well.
+    fn mark_edited(&mut self, x: i32, y: i32, z: i32) {
+        let touching = |c: i32| {
+            let low = if c == 0 { -1 } else { 0 };
+            let high = if c == CHUNK_DIM - 1 { 1 } else { 0 };
+            low..=high
+        };
+
         self.dirty = true;
-        &mut self.voxels[(z + y * CHUNK_DIM * CHUNK_DIM + x * CHUNK_DIM) as usize]
+        for dx in touching(x) {
+            for dy in touching(y) {
+                for dz in touching(z) {
+                    self.edited_neighbours[neighbour_slot(dx, dy, dz)] = true;
+                }
+            }
+        }
+    }
+
+    /// The voxel is written back into the palette once the returned guard is
+    /// dropped.
+    pub fn voxel_mut(&mut self, x: i32, y: i32, z: i32) -> VoxelMut<'_> {
+        self.mark_edited(x, y, z);
+        let i = voxel_offset(x, y, z);
+        VoxelMut {
+            voxel: *self.voxels.get(i),
+            voxels: &mut self.voxels,
+            i,
+        }
     }
 
     pub fn voxel(&self, x: i32, y: i32, z: i32) -> &Voxel {
-        &self.voxels[(z + y * CHUNK_DIM * CHUNK_DIM + x * CHUNK_DIM) as usize]
+        self.voxels.get(voxel_offset(x, y, z))
+    }
+
+    pub fn light(&self, x: i32, y: i32, z: i32) -> Light {
+        self.light.get(voxel_offset(x, y, z))
+    }
+
+    pub fn set_light(&mut self, x: i32, y: i32, z: i32, light: Light) {
+        let i = voxel_offset(x, y, z);
+        if self.light.get(i) != light {
+            self.light.set(i, light);
+            self.mark_edited(x, y, z);
+        }
+    }
+
+    /// Bytes used by the chunk, including its voxel palette and light.
+    pub fn memory_usage(&self) -> usize {
+        mem::size_of::<Chunk>() + self.voxels.heap_usage() + self.light.heap_usage()
     }
 
     pub fn gen_flat(ground: i32) -> Chunk {
+        Chunk::gen_layers(0, &[(VoxelType::GROUND, ground)])
+    }
+
+    /// The chunk starting at height `origin_y` of a world made of horizontal
+    /// layers, given bottom first as voxel type and thickness. The layers are
+    /// stacked up from y = 0, there's nothing below or above them. Together
+    /// they have to fit below `i32::MAX`, like `superflat::parse_layers`
+    /// makes sure.
+    pub fn gen_layers(origin_y: i32, layers: &[(VoxelType, i32)]) -> Chunk {
         let mut chunk = Chunk::void();
 
-        chunk.iter_mut(|(_, y, _), v| {
-            *v = if y < ground {
-                Voxel {
-                    voxel_type: VoxelType::GROUND,
-                }
-            } else {
-                Voxel::void()
+        let mut column = vec![Voxel::void(); CHUNK_DIM as usize];
+        let mut bottom = 0;
+        for (voxel_type, count) in layers {
+            for y in bottom.max(origin_y)..(bottom + count).min(origin_y.saturating_add(CHUNK_DIM))
+            {
+                column[(y - origin_y) as usize] = Voxel::new(*voxel_type);
             }
-        });
+            bottom += count;
+        }
+
+        chunk.iter_mut(|(_, y, _), v| *v = column[y as usize]);
 
         chunk
     }
+}
 
-    pub fn gen_vertex_array(&mut self) -> (u32, u32) {
-        let mut vertices = Vec::new();
-        let mut indices = Vec::new();
+/// A voxel of a chunk being changed, see `Chunk::voxel_mut`.
+pub struct VoxelMut<'a> {
+    voxels: &'a mut PalettedVoxels,
+    i: usize,
+    voxel: Voxel,
+}
 
-        let mut i = 0;
-        self.iter_mut(|(x, y, z), tile| {
-            if tile.voxel_type == VoxelType::VOID {
-                return;
-            }
-            // top plane
-            vertices.push(BasicVertex::from_pos(
-                x as f32 + 0.0,
-                y as f32 + 0.0,
-                z as f32 + 1.0,
-            ));
-            vertices.push(BasicVertex::from_pos(
-                x as f32 + 1.0,
-                y as f32 + 0.0,
-                z as f32 + 1.0,
-            ));
-            vertices.push(BasicVertex::from_pos(
-                x as f32 + 1.0,
-                y as f32 + 1.0,
-                z as f32 + 1.0,
-            ));
-            vertices.push(BasicVertex::from_pos(
-                x as f32 + 0.0,
-                y as f32 + 1.0,
-                z as f32 + 1.0,
-            ));
-            // bottom plane
-            vertices.push(BasicVertex::from_pos(
-                x as f32 + 0.0,
-                y as f32 + 0.0,
-                z as f32 + 0.0,
-            ));
-            vertices.push(BasicVertex::from_pos(
-                x as f32 + 1.0,
-                y as f32 + 0.0,
-                z as f32 + 0.0,
-            ));
-            vertices.push(BasicVertex::from_pos(
-                x as f32 + 1.0,
-                y as f32 + 1.0,
-                z as f32 + 0.0,
-            ));
-            vertices.push(BasicVertex::from_pos(
-                x as f32 + 0.0,
-                y as f32 + 1.0,
-                z as f32 + 0.0,
-            ));
-
-            indices.append(&mut vec![
-                // top face
-                i + 0,
-                i + 1,
-                i + 2,
-                i + 0,
-                i + 2,
-                i + 3,
-                // bottom face
-                i + 4,
-                i + 5,
-                i + 6,
-                i + 4,
-                i + 6,
-                i + 7,
-                // left face
-                i + 0,
-                i + 4,
-                i + 3,
-                i + 3,
-                i + 4,
-                i + 7,
-                // right face
-                i + 1,
-                i + 2,
-                i + 6,
-                i + 1,
-                i + 6,
-                i + 5,
-                // front face
-                i + 0,
-                i + 1,
-                i + 5,
-                i + 0,
-                i + 5,
-                i + 4,
-                // back face
-                i + 2,
-                i + 3,
-                i + 7,
-                i + 2,
-                i + 7,
-                i + 6,
-            ]);
-
-            i += 8;
-        });
-
-        let mut vao = 0;
-        let mut vbo = 0;
-        let mut ebo = 0;
-
-        unsafe {
-            gl::CreateVertexArrays(1, &mut vao);
-            gl::CreateBuffers(1, &mut vbo);
-            gl::CreateBuffers(1, &mut ebo);
-
-            gl::BindVertexArray(vao);
-
-            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
-            gl::NamedBufferData(
-                vbo,
-                (mem::size_of::<BasicVertex>() * vertices.len()) as isize,
-                vertices.as_ptr() as *const GLvoid,
-                gl::STATIC_DRAW,
-            );
-
-            gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, ebo);
-            gl::NamedBufferData(
-                ebo,
-                (mem::size_of::<u32>() * indices.len()) as isize,
-                indices.as_ptr() as *const GLvoid,
-                gl::STATIC_DRAW,
-            );
-
-            // Positions
-            gl::VertexAttribPointer(
-                0,
-                3,
-                gl::FLOAT,
-                0,
-                mem::size_of::<BasicVertex>() as i32,
-                ptr::null(),
-            );
-            gl::EnableVertexAttribArray(0);
-
-            gl::BindVertexArray(0);
-        }
+impl<'a> Deref for VoxelMut<'a> {
+    type Target = Voxel;
+
+    fn deref(&self) -> &Voxel {
+        &self.voxel
+    }
+}
 
-        (vao, indices.len() as u32)
+impl<'a> DerefMut for VoxelMut<'a> {
+    fn deref_mut(&mut self) -> &mut Voxel {
+        &mut self.voxel
+    }
+}
+
+impl<'a> Drop for VoxelMut<'a> {
+    fn drop(&mut self) {
+        if *self.voxels.get(self.i) != self.voxel {
+            self.voxels.set(self.i, self.voxel);
+        }
     }
 }
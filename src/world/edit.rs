@@ -0,0 +1,77 @@
+use crate::world::light::LightEngine;
+use crate::world::{registry, Voxel, VoxelIndex, World};
+
+use cgmath::Vector3;
+
+impl World {
+    /// Replaces a voxel and returns the previous one.
+    ///
+    /// The owning chunk is marked dirty, as are the neighbours sharing a border
+    /// with the voxel, and the light around it is updated. Returns `None` and
+    /// changes nothing if the chunk isn't loaded.
+    pub fn set_voxel(&mut self, index: VoxelIndex, voxel: Voxel) -> Option<Voxel> {
+        let previous = self.replace_voxel(index, voxel)?;
+        if previous != voxel {
+            if let Some(chunk) = self.chunk_mut(index.chunk_index()) {
+                chunk.modified = true;
+            }
+        }
+        Some(previous)
+    }
+
+    /// `set_voxel` for the generator's own changes, the chunk isn't counted as
+    /// edited by the player.
+    pub(super) fn replace_voxel(&mut self, index: VoxelIndex, voxel: Voxel) -> Option<Voxel> {
+        let i = index.chunk_index();
+        let local = index.local_part();
+
+        let previous = *self.try_chunk(i)?.voxel(local.x, local.y, local.z);
+        if previous == voxel {
+            return Some(previous);
+        }
+
+        *self
+            .chunk_mut(i)
+            .unwrap()
+            .voxel_mut(local.x, local.y, local.z) = voxel;
+        LightEngine::new(registry::get()).voxel_changed(&mut self.chunks, index, previous);
+        self.propagate_edited_sides(i);
+
+        Some(previous)
+    }
+
+    /// Applies a batch of edits in order, returning the previous value of each
+    /// like `set_voxel` does.
+    pub fn set_voxels<I>(&mut self, edits: I) -> Vec<Option<Voxel>>
+    where
+        I: IntoIterator<Item = (VoxelIndex, Voxel)>,
+    {
+        edits
+            .into_iter()
+            .map(|(index, voxel)| self.set_voxel(index, voxel))
+            .collect()
+    }
+
+    /// Fills the box between two corners (both inclusive) with a voxel.
+    /// Returns the voxels that were actually replaced, which is enough to undo
+    /// the fill with `set_voxels`.
+    pub fn fill(&mut self, a: VoxelIndex, b: VoxelIndex, voxel: Voxel) -> Vec<(VoxelIndex, Voxel)> {
+        let min = Vector3::new(a.0.x.min(b.0.x), a.0.y.min(b.0.y), a.0.z.min(b.0.z));
+        let max = Vector3::new(a.0.x.max(b.0.x), a.0.y.max(b.0.y), a.0.z.max(b.0.z));
+
+        let mut replaced = Vec::new();
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    let index = VoxelIndex::new(x, y, z);
+                    match self.set_voxel(index, voxel) {
+                        Some(previous) if previous != voxel => replaced.push((index, previous)),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        replaced
+    }
+}
@@ -0,0 +1,348 @@
+/// Most iterations a preset can ask for. Every iteration widens the margin
+/// eroded around a tile, so this bounds the work per tile.
+const MAX_ITERATIONS: usize = 48;
+
+/// Neighbours water flows to, in the order they're summed. Keeping the order
+/// fixed gives every column the same result whichever tile it's eroded in.
+const DIRECTIONS: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+
+/// Grid based hydraulic erosion of the height map.
+///
+/// Every iteration it rains on each column. Water flows to the lower
+/// neighbours, evening out the water level, and the more of it leaves a
+/// column the more sediment it can carry. Water below that `capacity` picks
+/// up ground at the `strength` rate, water above it drops the extra at the
+/// `deposition` rate, and `evaporation` takes a share of the water away. What
+/// sediment is left at the end is dropped where it is. Water gathers in
+/// valleys and cuts channels into them, and drops what it carried in fans
+/// where the ground flattens out.
+///
+/// A column only exchanges water and sediment with its direct neighbours, so
+/// after an iteration its height depends on columns at most two away. A tile
+/// is eroded together with a margin of `2 * iterations` columns, which gives
+/// its columns the same heights as in an endless height map and the tiles
+/// fit together without seams.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Erosion {
+    iterations: usize,
+    rain: f64,
+    evaporation: f64,
+    capacity: f64,
+    strength: f64,
+    deposition: f64,
+}
+
+impl Default for Erosion {
+    fn default() -> Erosion {
+        Erosion {
+            iterations: 32,
+            rain: 0.02,
+            evaporation: 0.05,
+            capacity: 1.0,
+            strength: 0.3,
+            deposition: 0.3,
+        }
+    }
+}
+
+impl Erosion {
+    /// Reads the optional `erosion` section of a terrain preset. Without one
+    /// the height map isn't eroded.
+    pub fn new(preset: &serde_json::Value) -> Result<Option<Erosion>, String> {
+        let section = match &preset["erosion"] {
+            serde_json::Value::Null => return Ok(None),
+            section => section,
+        };
+        let defaults = Erosion::default();
+        let number = |name: &str, default: f64, max: f64| match &section[name] {
+            serde_json::Value::Null => Ok(default),
+            value => value
+                .as_f64()
+                .filter(|value| *value >= 0.0 && *value <= max)
+                .ok_or_else(|| format!("erosion.{} needs to be a number from 0 to {}", name, max)),
+        };
+
+        let iterations = match &section["iterations"] {
+            serde_json::Value::Null => defaults.iterations,
+            value => value
+                .as_u64()
+                .filter(|iterations| *iterations <= MAX_ITERATIONS as u64)
+                .ok_or_else(|| {
+                    format!(
+                        "erosion.iterations needs to be a whole number from 0 to {}",
+                        MAX_ITERATIONS
+                    )
+                })? as usize,
+        };
+
+        Ok(Some(Erosion {
+            iterations,
+            rain: number("rain", defaults.rain, f64::INFINITY)?,
+            evaporation: number("evaporation", defaults.evaporation, 1.0)?,
+            capacity: number("capacity", defaults.capacity, f64::INFINITY)?,
+            strength: number("strength", defaults.strength, 1.0)?,
+            deposition: number("deposition", defaults.deposition, 1.0)?,
+        }))
+    }
+
+    /// Columns around a tile that are eroded along with it.
+    pub fn margin(&self) -> i32 {
+        2 * self.iterations as i32
+    }
+
+    /// Erodes the `size` by `size` tile of the height map starting at column
+    /// `(x, z)`. `height` gives the height of a column before erosion. The
+    /// heights come out ordered like `StageContext::columns`, x major.
+    pub fn erode_tile<F>(&self, x: i32, z: i32, size: i32, height: F) -> Vec<f64>
+    where
+        F: Fn(i32, i32) -> f64,
+    {
+        let margin = self.margin();
+        let width = size + 2 * margin;
+        let mut grid = Grid::new(width as usize, |gx, gz| {
+            height(x - margin + gx as i32, z - margin + gz as i32)
+        });
+        self.run(&mut grid);
+
+        let mut heights = Vec::with_capacity((size * size) as usize);
+        for tx in 0..size {
+            for tz in 0..size {
+                let cell = grid.cell((tx + margin) as usize, (tz + margin) as usize);
+                heights.push(grid.terrain[cell]);
+            }
+        }
+        heights
+    }
+
+    /// Runs the whole simulation on a grid, its edges are treated as walls.
+    fn run(&self, grid: &mut Grid) {
+        for _ in 0..self.iterations {
+            self.step(grid);
+        }
+        for (terrain, sediment) in grid.terrain.iter_mut().zip(&mut grid.sediment) {
+            *terrain += *sediment;
+            *sediment = 0.0;
+        }
+    }
+
+    fn step(&self, grid: &mut Grid) {
+        let width = grid.width;
+        let n_cells = width * width;
+        for water in &mut grid.water {
+            *water += self.rain;
+        }
+
+        // Water leaving every column in each direction, half of the
+        // difference in water level so neighbours don't overshoot
+        let mut outflow = vec![[0.0; 4]; n_cells];
+        let mut leaving = vec![0.0; n_cells];
+        for cell in 0..n_cells {
+            let level = grid.terrain[cell] + grid.water[cell];
+            let mut drops = [0.0; 4];
+            let mut total = 0.0;
+            for (d, neighbour) in grid.neighbours(cell).iter().enumerate() {
+                if let Some(neighbour) = *neighbour {
+                    let other = grid.terrain[neighbour] + grid.water[neighbour];
+                    drops[d] = (level - other).max(0.0);
+                    total += drops[d];
+                }
+            }
+            if total > 0.0 {
+                let amount = grid.water[cell].min(total / 2.0);
+                for d in 0..4 {
+                    outflow[cell][d] = amount * drops[d] / total;
+                }
+                leaving[cell] = amount;
+            }
+        }
+
+        // Fast water picks up ground, slow water drops it
+        let mut terrain = grid.terrain.clone();
+        for cell in 0..n_cells {
+            let capacity = self.capacity * leaving[cell];
+            let sediment = grid.sediment[cell];
+            let exchanged = if sediment < capacity {
+                // Never deeper than the lowest neighbour, that only digs pits
+                let lowest = grid
+                    .neighbours(cell)
+                    .iter()
+                    .flatten()
+                    .map(|neighbour| grid.terrain[cell] - grid.terrain[*neighbour])
+                    .fold(0.0, f64::max);
+                -(self.strength * (capacity - sediment)).min(lowest)
+            } else {
+                self.deposition * (sediment - capacity)
+            };
+            terrain[cell] += exchanged;
+            grid.sediment[cell] -= exchanged;
+        }
+        grid.terrain = terrain;
+
+        // Sediment travels with its share of the water
+        let mut water = vec![0.0; n_cells];
+        let mut sediment = vec![0.0; n_cells];
+        for cell in 0..n_cells {
+            let carried = if grid.water[cell] > 0.0 {
+                grid.sediment[cell] * leaving[cell] / grid.water[cell]
+            } else {
+                0.0
+            };
+            let mut w = grid.water[cell] - leaving[cell];
+            let mut s = grid.sediment[cell] - carried;
+            for (d, neighbour) in grid.neighbours(cell).iter().enumerate() {
+                if let Some(neighbour) = *neighbour {
+                    // The neighbour's flow back in the opposite direction
+                    let flow = outflow[neighbour][d ^ 1];
+                    w += flow;
+                    if flow > 0.0 {
+                        s += grid.sediment[neighbour] * flow / grid.water[neighbour];
+                    }
+                }
+            }
+            water[cell] = w * (1.0 - self.evaporation);
+            sediment[cell] = s;
+        }
+        grid.water = water;
+        grid.sediment = sediment;
+    }
+}
+
+/// A square of columns with their ground, water and suspended sediment.
+struct Grid {
+    width: usize,
+    terrain: Vec<f64>,
+    water: Vec<f64>,
+    sediment: Vec<f64>,
+}
+
+impl Grid {
+    fn new<F>(width: usize, height: F) -> Grid
+    where
+        F: Fn(usize, usize) -> f64,
+    {
+        let mut terrain = Vec::with_capacity(width * width);
+        for x in 0..width {
+            for z in 0..width {
+                terrain.push(height(x, z));
+            }
+        }
+        Grid {
+            width,
+            terrain,
+            water: vec![0.0; width * width],
+            sediment: vec![0.0; width * width],
+        }
+    }
+
+    fn cell(&self, x: usize, z: usize) -> usize {
+        x * self.width + z
+    }
+
+    /// The neighbours of a cell in `DIRECTIONS`, `None` past the edges.
+    fn neighbours(&self, cell: usize) -> [Option<usize>; 4] {
+        let (x, z) = ((cell / self.width) as i32, (cell % self.width) as i32);
+        let width = self.width as i32;
+        let mut neighbours = [None; 4];
+        for (d, (dx, dz)) in DIRECTIONS.iter().enumerate() {
+            let (nx, nz) = (x + dx, z + dz);
+            if nx >= 0 && nx < width && nz >= 0 && nz < width {
+                neighbours[d] = Some(self.cell(nx as usize, nz as usize));
+            }
+        }
+        neighbours
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A valley sloping down along x, with a bump to steer the water.
+    fn valley(x: i32, z: i32) -> f64 {
+        let bump = if (x - 3, z - 1) == (0, 0) { 2.0 } else { 0.0 };
+        40.0 - 2.0 * x as f64 + 0.5 * (z as f64 - 2.0).abs() + bump
+    }
+
+    fn erosion() -> Erosion {
+        Erosion {
+            iterations: 6,
+            rain: 0.5,
+            ..Erosion::default()
+        }
+    }
+
+    #[test]
+    pub fn tiles_fit_together() {
+        let erosion = erosion();
+        let whole = erosion.erode_tile(-8, -8, 16, valley);
+        for (tx, tz) in &[(-8, -8), (-8, 0), (0, -8), (0, 0)] {
+            let tile = erosion.erode_tile(*tx, *tz, 8, valley);
+            for x in 0..8 {
+                for z in 0..8 {
+                    let (wx, wz) = (tx + 8 + x, tz + 8 + z);
+                    assert_eq!(tile[(x * 8 + z) as usize], whole[(wx * 16 + wz) as usize]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn water_cuts_channels_and_drops_fans() {
+        // A slope into a flat plain, with a groove down the middle
+        let slope = |x: i32, z: i32| {
+            let ground = if x < 10 { 30.0 - 2.0 * x as f64 } else { 10.0 };
+            ground + (z as f64).abs() * 0.25
+        };
+        let erosion = Erosion {
+            iterations: 24,
+            ..erosion()
+        };
+        let eroded = erosion.erode_tile(0, -6, 16, slope);
+        let change = |x: i32, z: i32| eroded[(x * 16 + z + 6) as usize] - slope(x, z);
+
+        // The groove is dug deeper than the sides of the slope
+        assert!(change(6, 0) < -0.1);
+        assert!(change(6, 0) < change(6, 5));
+        // And what it carried ends up at the foot of the slope
+        assert!((10..14).map(|x| change(x, 0)).sum::<f64>() > 0.1);
+
+        // Nothing is lost or made out of thin air
+        let mut grid = Grid::new(20, |x, z| slope(x as i32, z as i32 - 10));
+        let before: f64 = grid.terrain.iter().sum();
+        erosion.run(&mut grid);
+        let after: f64 = grid.terrain.iter().sum();
+        assert!((before - after).abs() < 1e-9);
+    }
+
+    #[test]
+    pub fn golden_heightmap() {
+        // Changing these changes the terrain of every world with erosion
+        #[rustfmt::skip]
+        const GOLDEN: [f64; 36] = [
+            40.579769654611916, 36.97907096520024, 39.682935674847656,
+            38.083563598303655, 39.26982347068608, 39.53237904515939,
+            35.69512680434041, 38.569201077082724, 35.357287820152685,
+            37.12821916866502, 37.12818947484537, 37.515798452099766,
+            35.286204192213866, 35.52337321122416, 34.58596940231812,
+            34.79506932041682, 34.99630476357263, 35.62050666356526,
+            33.89173878101172, 32.21964360487371, 32.65151077265134,
+            32.46471944705184, 33.19709063170623, 33.523360047900304,
+            30.475728352407472, 31.211873824295125, 30.190008862261415,
+            30.758554092593734, 31.118159748442885, 31.533606276454652,
+            29.380756130024064, 28.604149808076315, 28.33257535992921,
+            28.715953481710407, 29.09395875049311, 29.554939424474636,
+        ];
+
+        let eroded = erosion().erode_tile(1, 0, 6, valley);
+        for (height, golden) in eroded.iter().zip(GOLDEN.iter()) {
+            assert!((height - golden).abs() < 1e-9, "{:?}", eroded);
+        }
+
+        let preset = serde_json::json!({ "erosion": { "iterations": 6, "rain": 0.5 } });
+        assert_eq!(Erosion::new(&preset), Ok(Some(erosion())));
+        assert_eq!(Erosion::new(&serde_json::json!({})), Ok(None));
+        assert!(Erosion::new(&serde_json::json!({ "erosion": { "iterations": 1000 } })).is_err());
+        assert!(Erosion::new(&serde_json::json!({ "erosion": { "iterations": 2.7 } })).is_err());
+        assert!(Erosion::new(&serde_json::json!({ "erosion": { "rain": "lots" } })).is_err());
+    }
+}
@@ -1,44 +1,435 @@
-use noise::{NoiseFn, Perlin};
+use crate::world::biome::{Biome, BiomeMap};
+use crate::world::caves::Caves;
+use crate::world::chunk::{self, CHUNK_DIM};
+use crate::world::erosion::Erosion;
+use crate::world::stages::{
+    self, Column, GenerationStage, StageContext, StageRegistry, StageTiming,
+};
+use crate::world::terrain::TerrainPreset;
+use crate::world::{voxel::Voxel, ChunkIndex, VoxelIndex};
 
-use crate::world::chunk;
-use crate::world::{voxel::Voxel, voxel::VoxelType, ChunkIndex};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-pub struct WorldGenerator {
-    noise: noise::Perlin,
+/// Chunk columns along each side of an eroded tile. Every tile is eroded
+/// with a margin around it, bigger tiles share that cost among more chunks.
+const ERODED_TILE_CHUNKS: i32 = 4;
+
+/// Eroded tiles kept around for the other chunks on them. Past this many the
+/// least recently used one is dropped.
+const MAX_ERODED_TILES: usize = 256;
+
+/// Derives the seed of a noise layer from the world seed, so layers built
+/// from the same kind of noise don't line up with each other.
+///
+/// This has to give the same result on every build and platform, or saved
+/// worlds would change under the player. That rules out `std`'s hashers, an
+/// FNV-1a hash of the layer name is mixed into the seed with SplitMix64
+/// instead.
+pub fn sub_seed(seed: u64, layer: &str) -> u32 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in layer.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    let mut z = (seed ^ hash).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 32) as u32
+}
+
+/// A random number for a column, the same for every chunk the column passes
+/// through.
+pub(super) fn column_hash(seed: u32, x: i32, z: i32) -> u64 {
+    let column = ((x as u32 as u64) << 32) | z as u32 as u64;
+    let mut h = column ^ (seed as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// Turns a hash into a number between 0 and 1.
+pub(super) fn roll(hash: u64) -> f64 {
+    (hash >> 11) as f64 / (1u64 << 53) as f64
 }
 
-const NOISE_SCALE: f64 = 100.0;
+/// Eroded heights of tiles of `ERODED_TILE_CHUNKS` by `ERODED_TILE_CHUNKS`
+/// chunk columns, by the tile's position in tiles.
+struct ErodedTiles {
+    /// The heights and when they were last used.
+    tiles: HashMap<(i32, i32), (u64, Arc<Vec<f64>>)>,
+    uses: u64,
+}
+
+impl ErodedTiles {
+    fn get(&mut self, tile: (i32, i32)) -> Option<Arc<Vec<f64>>> {
+        self.uses += 1;
+        let uses = self.uses;
+        self.tiles.get_mut(&tile).map(|(used, heights)| {
+            *used = uses;
+            heights.clone()
+        })
+    }
+
+    fn insert(&mut self, tile: (i32, i32), heights: Arc<Vec<f64>>) {
+        if self.tiles.len() >= MAX_ERODED_TILES && !self.tiles.contains_key(&tile) {
+            let oldest = self
+                .tiles
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(tile, _)| *tile);
+            if let Some(oldest) = oldest {
+                self.tiles.remove(&oldest);
+            }
+        }
+        self.uses += 1;
+        self.tiles.insert(tile, (self.uses, heights));
+    }
+}
+
+/// A stage of the generator and the time spent in it, summed over all chunk
+/// workers.
+struct Stage {
+    name: String,
+    stage: Box<dyn GenerationStage>,
+    nanos: AtomicU64,
+    runs: AtomicU64,
+}
+
+/// Generates the terrain of a world by running the stages of its preset on
+/// every chunk. The terrain only depends on the seed and the position, so
+/// chunks come out the same no matter when or on which thread they're
+/// generated.
+pub struct WorldGenerator {
+    seed: u64,
+    preset: TerrainPreset,
+    biomes: BiomeMap,
+    caves: Caves,
+    erosion: Option<Erosion>,
+    eroded: Mutex<ErodedTiles>,
+    stages: Vec<Stage>,
+}
 
 impl WorldGenerator {
-    pub fn new() -> WorldGenerator {
-        WorldGenerator {
-            noise: Perlin::new(),
+    pub fn new(seed: u64, preset: TerrainPreset) -> WorldGenerator {
+        WorldGenerator::with_stages(seed, preset, stages::get())
+            .expect("Terrain preset was validated.")
+    }
+
+    /// A generator taking the stages of its preset from a registry other than
+    /// the installed one.
+    pub fn with_stages(
+        seed: u64,
+        preset: TerrainPreset,
+        registry: &StageRegistry,
+    ) -> Result<WorldGenerator, String> {
+        let stages = registry
+            .build(preset.to_value(), seed)?
+            .into_iter()
+            .map(|(name, stage)| Stage {
+                name,
+                stage,
+                nanos: AtomicU64::new(0),
+                runs: AtomicU64::new(0),
+            })
+            .collect();
+
+        Ok(WorldGenerator {
+            seed,
+            biomes: preset.biomes(seed),
+            caves: preset.caves(seed),
+            erosion: preset.erosion(),
+            eroded: Mutex::new(ErodedTiles {
+                tiles: HashMap::new(),
+                uses: 0,
+            }),
+            preset,
+            stages,
+        })
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn preset(&self) -> &TerrainPreset {
+        &self.preset
+    }
+
+    /// The biome of the column a voxel is in.
+    pub fn biome_at(&self, index: VoxelIndex) -> &Biome {
+        self.biomes.biome_at(index.0.x, index.0.z)
+    }
+
+    /// Time spent in each stage so far, in the order they run.
+    pub fn stage_timings(&self) -> Vec<StageTiming> {
+        self.stages
+            .iter()
+            .map(|stage| StageTiming {
+                name: stage.name.clone(),
+                total: Duration::from_nanos(stage.nanos.load(Ordering::Relaxed)),
+                runs: stage.runs.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// The heights of the columns of a chunk after erosion, if the preset
+    /// erodes the height map. They're ordered like `StageContext::columns`.
+    fn eroded_heights(&self, i: ChunkIndex) -> Option<Vec<f64>> {
+        let erosion = self.erosion.as_ref()?;
+        let tile = (
+            i.0.x.div_euclid(ERODED_TILE_CHUNKS),
+            i.0.z.div_euclid(ERODED_TILE_CHUNKS),
+        );
+        let size = ERODED_TILE_CHUNKS * CHUNK_DIM;
+        let cached = self
+            .eroded
+            .lock()
+            .expect("Erosion cache poisoned.")
+            .get(tile);
+        let heights = match cached {
+            Some(heights) => heights,
+            None => {
+                // Eroded without holding the lock, at worst two workers erode
+                // the same tile and get the same heights
+                let heights = Arc::new(erosion.erode_tile(
+                    tile.0 * size,
+                    tile.1 * size,
+                    size,
+                    |x, z| self.biomes.height_at(x, z),
+                ));
+                self.eroded
+                    .lock()
+                    .expect("Erosion cache poisoned.")
+                    .insert(tile, heights.clone());
+                heights
+            }
+        };
+
+        // The chunk's part of the tile
+        let origin = i.chunk_origin();
+        let (x, z) = (origin.x - tile.0 * size, origin.z - tile.1 * size);
+        let mut columns = Vec::with_capacity((CHUNK_DIM * CHUNK_DIM) as usize);
+        for tx in x..x + CHUNK_DIM {
+            columns.extend_from_slice(
+                &heights[(tx * size + z) as usize..(tx * size + z + CHUNK_DIM) as usize],
+            );
         }
+        Some(columns)
     }
 
-    pub fn gen_chunk<C>(&mut self, i: C) -> chunk::Chunk
+    /// Generates a chunk, together with the voxels of its structures that
+    /// stick out into the neighbouring chunks. Those are only placed where a
+    /// neighbour has empty space, like the chunk's own structures.
+    pub fn gen_chunk<C>(&self, i: C) -> (chunk::Chunk, Vec<(VoxelIndex, Voxel)>)
     where
         C: Into<ChunkIndex>,
     {
         let mut chunk = chunk::Chunk::void();
+        let mut spilled = Vec::new();
         let i: ChunkIndex = i.into();
 
-        let sampler = &self.noise;
+        let origin = i.chunk_origin();
+        let eroded = self.eroded_heights(i);
+        let mut columns = Vec::with_capacity((CHUNK_DIM * CHUNK_DIM) as usize);
+        for x in 0..CHUNK_DIM {
+            for z in 0..CHUNK_DIM {
+                let (wx, wz) = (origin.x + x, origin.z + z);
+                columns.push(Column {
+                    biome: self.biomes.biome_at(wx, wz),
+                    height: match &eroded {
+                        Some(heights) => heights[(x * CHUNK_DIM + z) as usize],
+                        None => self.biomes.height_at(wx, wz),
+                    },
+                });
+            }
+        }
 
-        chunk.iter_mut(|(x, y, z), v| {
-            let x = i.chunk_origin().x + x as i32;
-            let z = i.chunk_origin().z + z as i32;
+        let stages: Vec<&dyn GenerationStage> =
+            self.stages.iter().map(|stage| &*stage.stage).collect();
+        let mut context = StageContext {
+            seed: self.seed,
+            index: i,
+            caves: &self.caves,
+            columns: &columns,
+            chunk: &mut chunk,
+            spilled: &mut spilled,
+            earlier: &[],
+        };
+        for (n, stage) in self.stages.iter().enumerate() {
+            context.earlier = &stages[..n];
+            let start = Instant::now();
+            stage.stage.run(&mut context);
+            let nanos = start.elapsed().as_nanos() as u64;
+            stage.nanos.fetch_add(nanos, Ordering::Relaxed);
+            stage.runs.fetch_add(1, Ordering::Relaxed);
+        }
 
-            let sampled_height = sampler.get([x as f64 / NOISE_SCALE, z as f64 / NOISE_SCALE]);
-            *v = if (y as f64) < (sampled_height * (chunk::CHUNK_HEIGHT - 80) as f64 + 40.0) {
-                Voxel {
-                    voxel_type: VoxelType::GROUND,
-                }
-            } else {
-                Voxel::void()
+        (chunk, spilled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::region::encode_chunk;
+    use crate::world::VoxelType;
+
+    use std::thread;
+
+    const SAMPLES: [(i32, i32, i32); 4] = [(0, 2, 0), (-3, 1, 7), (12, 3, -5), (-40, 2, -41)];
+
+    fn generate_all(generator: &WorldGenerator, order: &[(i32, i32, i32)]) -> Vec<Vec<u8>> {
+        let mut chunks: Vec<_> = order
+            .iter()
+            .map(|i| (*i, encode_chunk(&generator.gen_chunk(*i).0)))
+            .collect();
+        chunks.sort_by_key(|(i, _)| *i);
+        chunks.into_iter().map(|(_, bytes)| bytes).collect()
+    }
+
+    #[test]
+    pub fn sub_seeds_are_stable() {
+        assert_eq!(sub_seed(42, "height"), sub_seed(42, "height"));
+        assert_ne!(sub_seed(42, "height"), sub_seed(43, "height"));
+        assert_ne!(sub_seed(42, "height"), sub_seed(42, "caves"));
+        // Changing these breaks every saved world
+        assert_eq!(sub_seed(0, ""), 0xc381_7c01);
+    }
+
+    #[test]
+    pub fn same_seed_gives_identical_chunks() {
+        let generator = WorldGenerator::new(1234, TerrainPreset::default());
+        let in_order = generate_all(&generator, &SAMPLES);
+
+        // Reversed, on another thread, with another generator
+        let mut reversed = SAMPLES;
+        reversed.reverse();
+        let elsewhere = thread::spawn(move || {
+            generate_all(
+                &WorldGenerator::new(1234, TerrainPreset::default()),
+                &reversed,
+            )
+        })
+        .join()
+        .unwrap();
+        assert_eq!(in_order, elsewhere);
+
+        // Generating a chunk twice doesn't change it either
+        assert_eq!(in_order, generate_all(&generator, &SAMPLES));
+
+        let other_seed = generate_all(
+            &WorldGenerator::new(4321, TerrainPreset::default()),
+            &SAMPLES,
+        );
+        assert_ne!(in_order, other_seed);
+    }
+
+    #[test]
+    pub fn decorations_cross_section_borders() {
+        let preset = TerrainPreset::from_json(
+            r#"{ "biomes": [{
+                "name": "pillars", "temperature": 0, "humidity": 0,
+                "height": { "type": "constant", "value": 14.5 },
+                "decorations": [{ "block": "dirt", "chance": 1, "height": [3, 3] }]
+            }] }"#,
+        )
+        .unwrap();
+        let generator = WorldGenerator::new(5, preset);
+        let pillar = Voxel::new(VoxelType::GROUND);
+
+        // The surface is at 14, the pillar takes up 15 to 17
+        let (below, _) = generator.gen_chunk((2, 0, -1));
+        let (above, _) = generator.gen_chunk((2, 1, -1));
+        assert_eq!(*below.voxel(4, 15, 9), pillar);
+        assert_eq!(*above.voxel(4, 0, 9), pillar);
+        assert_eq!(*above.voxel(4, 1, 9), pillar);
+        assert_eq!(*above.voxel(4, 2, 9), Voxel::void());
+        assert_eq!(
+            generator.biome_at(VoxelIndex::new(40, 16, -7)).name,
+            "pillars"
+        );
+    }
+
+    #[test]
+    pub fn chunks_share_eroded_tiles() {
+        let preset = TerrainPreset::from_json(
+            r#"{
+                "height": { "type": "perlin", "frequency": 0.05 },
+                "erosion": { "iterations": 4, "rain": 0.5 }
+            }"#,
+        )
+        .unwrap();
+        let generator = WorldGenerator::new(6, preset);
+        let erosion = generator.erosion.clone().unwrap();
+
+        // A chunk's part of its tile is eroded as if it were eroded alone
+        for i in &[(0, 0, 0), (-1, 2, 5), (-5, 0, -9)] {
+            let index: ChunkIndex = (*i).into();
+            let origin = index.chunk_origin();
+            let alone = erosion.erode_tile(origin.x, origin.z, CHUNK_DIM, |x, z| {
+                generator.biomes.height_at(x, z)
+            });
+            let shared = generator.eroded_heights(index).unwrap();
+            for (a, b) in alone.iter().zip(shared.iter()) {
+                assert!((a - b).abs() < 1e-9);
             }
-        });
+        }
+        assert_eq!(generator.eroded.lock().unwrap().tiles.len(), 3);
 
-        chunk
+        // Full, the tile that went unused longest makes room
+        let mut tiles = ErodedTiles {
+            tiles: HashMap::new(),
+            uses: 0,
+        };
+        for n in 0..MAX_ERODED_TILES as i32 {
+            tiles.insert((n, 0), Arc::new(Vec::new()));
+        }
+        assert!(tiles.get((0, 0)).is_some());
+        tiles.insert((-1, 0), Arc::new(Vec::new()));
+        assert_eq!(tiles.tiles.len(), MAX_ERODED_TILES);
+        assert!(tiles.get((0, 0)).is_some());
+        assert!(tiles.get((1, 0)).is_none());
+    }
+
+    #[test]
+    pub fn caves_continue_across_chunks() {
+        let preset = TerrainPreset::from_json(
+            r#"{
+                "height": { "type": "constant", "value": 1000 },
+                "caves": {
+                    "density": { "type": "perlin", "frequency": 0.15 },
+                    "threshold": 0.2
+                }
+            }"#,
+        )
+        .unwrap();
+        let generator = WorldGenerator::new(9, preset);
+
+        // Every voxel is carved by the noise at its world position, wherever
+        // the chunk it's in starts
+        let mut carved = 0;
+        for i in &[(0, 0, 0), (1, 0, 0), (-1, 3, 2)] {
+            let index: ChunkIndex = (*i).into();
+            let origin = index.chunk_origin();
+            let (chunk, _) = generator.gen_chunk(index);
+            for x in 0..CHUNK_DIM {
+                for y in 0..CHUNK_DIM {
+                    for z in 0..CHUNK_DIM {
+                        let expected =
+                            generator
+                                .caves
+                                .is_carved(origin.x + x, origin.y + y, origin.z + z);
+                        assert_eq!(*chunk.voxel(x, y, z) == Voxel::void(), expected);
+                        carved += expected as usize;
+                    }
+                }
+            }
+        }
+        assert!(carved > 0);
     }
 }
@@ -0,0 +1,140 @@
+use crate::world::gen::WorldGenerator;
+use crate::world::mesher::{ChunkMesh, Neighbours};
+use crate::world::region::RegionStore;
+use crate::world::{Chunk, ChunkIndex, Voxel, VoxelIndex};
+
+use std::collections::HashSet;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub enum Job {
+    /// Reads a chunk from the store if it was saved there, generates it
+    /// otherwise.
+    Generate(ChunkIndex, Option<Arc<RegionStore>>),
+    /// Meshes a chunk and its neighbourhood (indexed by
+    /// `chunk::neighbour_slot`), shared with the world. Edits made in the
+    /// meantime go into copies.
+    Mesh(ChunkIndex, Arc<Chunk>, Vec<Option<Arc<Chunk>>>),
+}
+
+pub enum JobResult {
+    /// A chunk and the structure voxels it spills into its neighbours.
+    Generated(ChunkIndex, Chunk, Vec<(VoxelIndex, Voxel)>),
+    Meshed(ChunkIndex, ChunkMesh),
+}
+
+/// A fixed set of threads generating and meshing chunks off the main thread.
+/// Finished jobs are collected with `poll`.
+pub struct WorkerPool {
+    jobs: Option<Sender<Job>>,
+    results: Receiver<JobResult>,
+    cancelled: Arc<Mutex<HashSet<ChunkIndex>>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(generator: Arc<WorldGenerator>, n_workers: usize) -> WorkerPool {
+        let (job_sender, job_receiver) = channel::<Job>();
+        let (result_sender, results) = channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let cancelled = Arc::new(Mutex::new(HashSet::new()));
+
+        let workers = (0..n_workers.max(1))
+            .map(|n| {
+                let jobs = job_receiver.clone();
+                let results: Sender<JobResult> = result_sender.clone();
+                let cancelled = cancelled.clone();
+                let generator = generator.clone();
+
+                thread::Builder::new()
+                    .name(format!("chunk worker {}", n))
+                    .spawn(move || loop {
+                        let job = match jobs.lock().expect("Job queue poisoned.").recv() {
+                            Ok(job) => job,
+                            // The pool was dropped
+                            Err(_) => return,
+                        };
+
+                        let result = match job {
+                            Job::Generate(i, store) => {
+                                if cancelled.lock().expect("Cancel set poisoned.").remove(&i) {
+                                    continue;
+                                }
+
+                                let saved = store.and_then(|store| match store.read_chunk(i) {
+                                    Ok(chunk) => chunk,
+                                    Err(e) => {
+                                        println!("Failed to load chunk {:?}: {}", i, e);
+                                        None
+                                    }
+                                });
+                                // Saved chunks still spill their structures
+                                // into neighbours fresh from the generator
+                                let (generated, spilled) = generator.gen_chunk(i);
+                                JobResult::Generated(i, saved.unwrap_or(generated), spilled)
+                            }
+                            Job::Mesh(i, chunk, neighbours) => {
+                                let mut borrowed = Neighbours::default();
+                                for (slot, n) in neighbours.iter().enumerate() {
+                                    borrowed.chunks[slot] = n.as_deref();
+                                }
+                                JobResult::Meshed(i, ChunkMesh::build(&chunk, &borrowed))
+                            }
+                        };
+
+                        if results.send(result).is_err() {
+                            return;
+                        }
+                    })
+                    .expect("Failed to spawn chunk worker.")
+            })
+            .collect();
+
+        WorkerPool {
+            jobs: Some(job_sender),
+            results,
+            cancelled,
+            workers,
+        }
+    }
+
+    pub fn submit(&self, job: Job) {
+        if let Job::Generate(i, _) = &job {
+            self.cancelled
+                .lock()
+                .expect("Cancel set poisoned.")
+                .remove(i);
+        }
+
+        self.jobs
+            .as_ref()
+            .expect("Worker pool is shut down.")
+            .send(job)
+            .expect("All chunk workers died.");
+    }
+
+    /// Skips a queued `Job::Generate` if no worker has picked it up yet.
+    /// Results which are already on their way still arrive through `poll`.
+    pub fn cancel(&self, i: ChunkIndex) {
+        self.cancelled
+            .lock()
+            .expect("Cancel set poisoned.")
+            .insert(i);
+    }
+
+    /// Returns the next finished job without blocking.
+    pub fn poll(&self) -> Option<JobResult> {
+        self.results.try_recv().ok()
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Closing the queue makes every worker return once it's idle
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,670 @@
+use crate::world::chunk::{Chunk, CHUNK_DIM, CHUNK_N_VOXELS, SIDES};
+use crate::world::registry::BlockRegistry;
+use crate::world::{ChunkIndex, ChunkRenderdata, Voxel, VoxelIndex};
+
+use cgmath::Vector3;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+/// Brightest light level, the level of open sky and the strongest emitters.
+pub const MAX_LIGHT: u8 = 15;
+
+/// Sky and block light level of a voxel, each between 0 and `MAX_LIGHT`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Light(u8);
+
+impl Light {
+    pub fn new(sky: u8, block: u8) -> Light {
+        Light((sky.min(MAX_LIGHT) << 4) | block.min(MAX_LIGHT))
+    }
+
+    /// Light coming from the sky, `MAX_LIGHT` in open air.
+    pub fn sky(self) -> u8 {
+        self.0 >> 4
+    }
+
+    /// Light coming from emissive voxels.
+    pub fn block(self) -> u8 {
+        self.0 & 0xf
+    }
+
+    fn get(self, channel: Channel) -> u8 {
+        match channel {
+            Channel::Sky => self.sky(),
+            Channel::Block => self.block(),
+        }
+    }
+
+    fn with(self, channel: Channel, level: u8) -> Light {
+        match channel {
+            Channel::Sky => Light::new(level, self.block()),
+            Channel::Block => Light::new(self.sky(), level),
+        }
+    }
+}
+
+/// Light of the voxels of a chunk.
+///
+/// Chunks of open sky or buried in the ground have the same light all over,
+/// they keep a single level until a voxel gets a different one. The light
+/// engine shrinks chunks back to that once it's done with them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkLight {
+    Uniform(Light),
+    Voxels(Box<[Light]>),
+}
+
+impl ChunkLight {
+    pub fn get(&self, i: usize) -> Light {
+        match self {
+            ChunkLight::Uniform(light) => *light,
+            ChunkLight::Voxels(voxels) => voxels[i],
+        }
+    }
+
+    pub fn set(&mut self, i: usize, light: Light) {
+        match self {
+            ChunkLight::Uniform(uniform) if *uniform == light => {}
+            ChunkLight::Uniform(uniform) => {
+                let mut voxels = vec![*uniform; CHUNK_N_VOXELS].into_boxed_slice();
+                voxels[i] = light;
+                *self = ChunkLight::Voxels(voxels);
+            }
+            ChunkLight::Voxels(voxels) => voxels[i] = light,
+        }
+    }
+
+    /// Goes back to a single level if every voxel has the same light.
+    pub fn compact(&mut self) {
+        if let ChunkLight::Voxels(voxels) = self {
+            let first = voxels[0];
+            if voxels.iter().all(|light| *light == first) {
+                *self = ChunkLight::Uniform(first);
+            }
+        }
+    }
+
+    /// Bytes allocated on the heap for the light of the voxels.
+    pub fn heap_usage(&self) -> usize {
+        match self {
+            ChunkLight::Uniform(_) => 0,
+            ChunkLight::Voxels(voxels) => voxels.len() * std::mem::size_of::<Light>(),
+        }
+    }
+}
+
+// Also the index of the channel's queues in the `LightEngine`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Channel {
+    Sky = 0,
+    Block = 1,
+}
+
+const CHANNELS: [Channel; 2] = [Channel::Sky, Channel::Block];
+
+/// Where the light engine finds the chunks it lights.
+pub trait LightStorage {
+    fn chunk(&self, i: ChunkIndex) -> Option<&Chunk>;
+    fn chunk_mut(&mut self, i: ChunkIndex) -> Option<&mut Chunk>;
+}
+
+impl LightStorage for HashMap<ChunkIndex, Chunk> {
+    fn chunk(&self, i: ChunkIndex) -> Option<&Chunk> {
+        self.get(&i)
+    }
+
+    fn chunk_mut(&mut self, i: ChunkIndex) -> Option<&mut Chunk> {
+        self.get_mut(&i)
+    }
+}
+
+impl LightStorage for HashMap<ChunkIndex, (Arc<Chunk>, ChunkRenderdata)> {
+    fn chunk(&self, i: ChunkIndex) -> Option<&Chunk> {
+        self.get(&i).map(|(chunk, _)| &**chunk)
+    }
+
+    fn chunk_mut(&mut self, i: ChunkIndex) -> Option<&mut Chunk> {
+        self.get_mut(&i).map(|(chunk, _)| Arc::make_mut(chunk))
+    }
+}
+
+// The six neighbours of a voxel, with a flag for the one right below it
+const STEPS: [([i32; 3], bool); 6] = [
+    ([-1, 0, 0], false),
+    ([1, 0, 0], false),
+    ([0, -1, 0], true),
+    ([0, 1, 0], false),
+    ([0, 0, -1], false),
+    ([0, 0, 1], false),
+];
+
+fn step(index: VoxelIndex, offset: [i32; 3]) -> VoxelIndex {
+    VoxelIndex(index.0 + Vector3::new(offset[0], offset[1], offset[2]))
+}
+
+/// Chunk and local position of a voxel.
+fn locate(index: VoxelIndex) -> (ChunkIndex, Vector3<i32>) {
+    (index.chunk_index(), index.local_part())
+}
+
+/// Flood fills sky and block light through the transparent voxels of a set of
+/// chunks, Minecraft style.
+///
+/// Sky light enters through the top of sections without a loaded section
+/// above them and travels straight down at full strength, block light starts
+/// at emissive voxels. Both lose one level per step sideways. Changes are
+/// propagated incrementally with a queue of voxels to brighten and a queue of
+/// voxels to darken, and light never spreads into chunks that aren't in the
+/// storage.
+pub struct LightEngine<'a> {
+    registry: &'a BlockRegistry,
+    add: [VecDeque<VoxelIndex>; 2],
+    remove: [VecDeque<(VoxelIndex, u8)>; 2],
+    /// Chunks whose light changed, they're compacted when it has settled.
+    lit: HashSet<ChunkIndex>,
+}
+
+impl<'a> LightEngine<'a> {
+    pub fn new(registry: &'a BlockRegistry) -> LightEngine<'a> {
+        LightEngine {
+            registry,
+            add: Default::default(),
+            remove: Default::default(),
+            lit: HashSet::new(),
+        }
+    }
+
+    fn opaque(&self, voxel: Voxel) -> bool {
+        self.registry.is_solid(voxel.voxel_type) && !self.registry.is_transparent(voxel.voxel_type)
+    }
+
+    fn emission(&self, voxel: Voxel) -> u8 {
+        self.registry
+            .get(voxel.voxel_type)
+            .map_or(0, |def| def.emission)
+    }
+
+    /// Computes the light of a chunk which was just added to `chunks`, and
+    /// exchanges light with its loaded neighbours.
+    pub fn light_chunk<S: LightStorage>(&mut self, chunks: &mut S, i: ChunkIndex) {
+        let origin = i.chunk_origin();
+        let index = |x: i32, y: i32, z: i32| VoxelIndex(origin + Vector3::new(x, y, z));
+
+        // Sunlight comes in from the section above, or from the open sky if
+        // that isn't loaded (yet).
+        let mut sky_from_above = [[MAX_LIGHT; CHUNK_DIM as usize]; CHUNK_DIM as usize];
+        if let Some(above) = chunks.chunk(i.offset(0, 1, 0)) {
+            for x in 0..CHUNK_DIM {
+                for z in 0..CHUNK_DIM {
+                    if above.light(x, 0, z).sky() < MAX_LIGHT {
+                        sky_from_above[x as usize][z as usize] = 0;
+                    }
+                }
+            }
+        }
+
+        {
+            let registry = self.registry;
+            let opaque = |voxel: &Voxel| {
+                registry.is_solid(voxel.voxel_type) && !registry.is_transparent(voxel.voxel_type)
+            };
+            let chunk = match chunks.chunk_mut(i) {
+                Some(chunk) => chunk,
+                None => return,
+            };
+            self.lit.insert(i);
+
+            for x in 0..CHUNK_DIM {
+                for z in 0..CHUNK_DIM {
+                    let mut sky = sky_from_above[x as usize][z as usize];
+                    for y in (0..CHUNK_DIM).rev() {
+                        let voxel = *chunk.voxel(x, y, z);
+                        if opaque(&voxel) {
+                            sky = 0;
+                        }
+
+                        let emission = registry.get(voxel.voxel_type).map_or(0, |def| def.emission);
+                        chunk.set_light(x, y, z, Light::new(sky, emission));
+                        if emission > 0 {
+                            self.add[Channel::Block as usize].push_back(index(x, y, z));
+                        }
+                    }
+                }
+            }
+
+            // Only the edges of the sunlit area spread any further
+            for x in 0..CHUNK_DIM {
+                for y in 0..CHUNK_DIM {
+                    for z in 0..CHUNK_DIM {
+                        if chunk.light(x, y, z).sky() < MAX_LIGHT {
+                            continue;
+                        }
+
+                        let edge = SIDES.iter().any(|(dx, dy, dz)| {
+                            let (nx, ny, nz) = (x + dx, y + dy, z + dz);
+                            if [nx, ny, nz].iter().any(|c| *c < 0 || *c >= CHUNK_DIM) {
+                                return true;
+                            }
+                            !opaque(chunk.voxel(nx, ny, nz))
+                                && chunk.light(nx, ny, nz).sky() < MAX_LIGHT
+                        });
+                        if edge {
+                            self.add[Channel::Sky as usize].push_back(index(x, y, z));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Pull in the light of the neighbours' facing borders
+        for offset in SIDES.iter() {
+            let (dx, dy, dz) = *offset;
+            let neighbour = match chunks.chunk(i.offset(dx, dy, dz)) {
+                Some(neighbour) => neighbour,
+                None => continue,
+            };
+
+            for k in 0..CHUNK_DIM {
+                for l in 0..CHUNK_DIM {
+                    // Local coordinates in the new chunk of the voxel across the border
+                    let across = |d: i32, free: i32| match d {
+                        -1 => -1,
+                        1 => CHUNK_DIM,
+                        _ => free,
+                    };
+                    let (x, y, z) = match offset {
+                        (_, 0, 0) => (across(dx, 0), k, l),
+                        (0, _, 0) => (k, across(dy, 0), l),
+                        _ => (k, l, across(dz, 0)),
+                    };
+                    let light =
+                        neighbour.light(x - dx * CHUNK_DIM, y - dy * CHUNK_DIM, z - dz * CHUNK_DIM);
+
+                    for channel in CHANNELS.iter() {
+                        if light.get(*channel) > 1 {
+                            self.add[*channel as usize].push_back(index(x, y, z));
+                        }
+                    }
+
+                    // A section below which was loaded first lit itself as if
+                    // it were under the open sky. Shadows cast by this one have
+                    // to be carried down into it.
+                    if *offset == (0, -1, 0)
+                        && light.sky() == MAX_LIGHT
+                        && self.level(chunks, index(x, 0, z), Channel::Sky) < Some(MAX_LIGHT)
+                    {
+                        self.remove[Channel::Sky as usize].push_back((index(x, y, z), MAX_LIGHT));
+                    }
+                }
+            }
+        }
+
+        let shadowed: Vec<VoxelIndex> = self.remove[Channel::Sky as usize]
+            .iter()
+            .map(|(index, _)| *index)
+            .collect();
+        for index in shadowed {
+            self.set_level(chunks, index, Channel::Sky, 0);
+        }
+
+        self.propagate(chunks);
+    }
+
+    /// Updates the light around a voxel which was changed from `previous` to
+    /// whatever is stored in `chunks` now.
+    pub fn voxel_changed<S: LightStorage>(
+        &mut self,
+        chunks: &mut S,
+        index: VoxelIndex,
+        previous: Voxel,
+    ) {
+        let voxel = match self.voxel(chunks, index) {
+            Some(voxel) => voxel,
+            None => return,
+        };
+        if voxel == previous {
+            return;
+        }
+
+        for channel in CHANNELS.iter() {
+            let n = *channel as usize;
+            let old = self.level(chunks, index, *channel).unwrap_or(0);
+            if old > 0 {
+                self.set_level(chunks, index, *channel, 0);
+                self.remove[n].push_back((index, old));
+            }
+
+            if !self.opaque(voxel) {
+                for (offset, _) in STEPS.iter() {
+                    let neighbour = step(index, *offset);
+                    if self.level(chunks, neighbour, *channel).unwrap_or(0) > 0 {
+                        self.add[n].push_back(neighbour);
+                    }
+                }
+
+                let above = step(index, [0, 1, 0]);
+                if *channel == Channel::Sky && self.level(chunks, above, *channel).is_none() {
+                    self.set_level(chunks, index, *channel, MAX_LIGHT);
+                    self.add[n].push_back(index);
+                }
+            }
+
+            let emission = self.emission(voxel);
+            if *channel == Channel::Block && emission > 0 {
+                self.set_level(chunks, index, *channel, emission);
+                self.add[n].push_back(index);
+            }
+        }
+
+        self.propagate(chunks);
+    }
+
+    fn voxel<S: LightStorage>(&self, chunks: &S, index: VoxelIndex) -> Option<Voxel> {
+        let (i, p) = locate(index);
+        chunks.chunk(i).map(|chunk| *chunk.voxel(p.x, p.y, p.z))
+    }
+
+    fn level<S: LightStorage>(
+        &self,
+        chunks: &S,
+        index: VoxelIndex,
+        channel: Channel,
+    ) -> Option<u8> {
+        let (i, p) = locate(index);
+        chunks
+            .chunk(i)
+            .map(|chunk| chunk.light(p.x, p.y, p.z).get(channel))
+    }
+
+    fn set_level<S: LightStorage>(
+        &mut self,
+        chunks: &mut S,
+        index: VoxelIndex,
+        channel: Channel,
+        level: u8,
+    ) {
+        let (i, p) = locate(index);
+        if let Some(chunk) = chunks.chunk_mut(i) {
+            let light = chunk.light(p.x, p.y, p.z).with(channel, level);
+            chunk.set_light(p.x, p.y, p.z, light);
+            self.lit.insert(i);
+        }
+    }
+
+    fn propagate<S: LightStorage>(&mut self, chunks: &mut S) {
+        self.spread(chunks);
+        for i in self.lit.drain() {
+            if let Some(chunk) = chunks.chunk_mut(i) {
+                chunk.light.compact();
+            }
+        }
+    }
+
+    fn spread<S: LightStorage>(&mut self, chunks: &mut S) {
+        for channel in CHANNELS.iter().cloned() {
+            let n = channel as usize;
+
+            // Darken everything that was lit by the removed light. Brighter
+            // neighbours have another source and fill the gap back in.
+            while let Some((index, level)) = self.remove[n].pop_front() {
+                for (offset, down) in STEPS.iter() {
+                    let neighbour = step(index, *offset);
+                    let l = match self.level(chunks, neighbour, channel) {
+                        Some(l) if l > 0 => l,
+                        _ => continue,
+                    };
+
+                    let sunbeam = channel == Channel::Sky && *down && level == MAX_LIGHT;
+                    if l < level || (sunbeam && l == MAX_LIGHT) {
+                        self.set_level(chunks, neighbour, channel, 0);
+                        self.remove[n].push_back((neighbour, l));
+
+                        let emission = match channel {
+                            Channel::Block => self
+                                .voxel(chunks, neighbour)
+                                .map_or(0, |voxel| self.emission(voxel)),
+                            Channel::Sky => 0,
+                        };
+                        if emission > 0 {
+                            self.set_level(chunks, neighbour, channel, emission);
+                            self.add[n].push_back(neighbour);
+                        }
+                    } else {
+                        self.add[n].push_back(neighbour);
+                    }
+                }
+            }
+
+            while let Some(index) = self.add[n].pop_front() {
+                let level = match self.level(chunks, index, channel) {
+                    Some(level) if level > 1 => level,
+                    _ => continue,
+                };
+
+                for (offset, down) in STEPS.iter() {
+                    let neighbour = step(index, *offset);
+                    match self.voxel(chunks, neighbour) {
+                        Some(voxel) if !self.opaque(voxel) => {}
+                        _ => continue,
+                    }
+
+                    let sunbeam = channel == Channel::Sky && *down && level == MAX_LIGHT;
+                    let next = if sunbeam { MAX_LIGHT } else { level - 1 };
+                    if self.level(chunks, neighbour, channel).unwrap_or(MAX_LIGHT) < next {
+                        self.set_level(chunks, neighbour, channel, next);
+                        self.add[n].push_back(neighbour);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::VoxelType;
+
+    const LAMP: VoxelType = VoxelType(4);
+
+    fn registry() -> BlockRegistry {
+        BlockRegistry::from_json(
+            r#"{ "blocks": [
+                { "id": 1, "name": "dirt" },
+                { "id": 2, "name": "glass", "transparent": true },
+                { "id": 4, "name": "lamp", "emission": 14 }
+            ] }"#,
+        )
+        .unwrap()
+    }
+
+    fn light_at(chunks: &HashMap<ChunkIndex, Chunk>, x: i32, y: i32, z: i32) -> Light {
+        let (i, p) = locate(VoxelIndex::new(x, y, z));
+        chunks[&i].light(p.x, p.y, p.z)
+    }
+
+    fn set_voxel(
+        chunks: &mut HashMap<ChunkIndex, Chunk>,
+        x: i32,
+        y: i32,
+        z: i32,
+        voxel_type: VoxelType,
+    ) -> Voxel {
+        let (i, p) = locate(VoxelIndex::new(x, y, z));
+        let mut voxel = chunks.get_mut(&i).unwrap().voxel_mut(p.x, p.y, p.z);
+        std::mem::replace(&mut *voxel, Voxel::new(voxel_type))
+    }
+
+    fn load(
+        registry: &BlockRegistry,
+        chunks: &mut HashMap<ChunkIndex, Chunk>,
+        i: (i32, i32, i32),
+        chunk: Chunk,
+    ) {
+        let i = i.into();
+        chunks.insert(i, chunk);
+        LightEngine::new(registry).light_chunk(chunks, i);
+    }
+
+    #[test]
+    pub fn sunlight_fills_open_columns() {
+        let registry = registry();
+        let mut chunks = HashMap::new();
+        load(&registry, &mut chunks, (0, 0, 0), Chunk::gen_flat(10));
+
+        assert_eq!(
+            light_at(&chunks, 3, CHUNK_DIM - 1, 3),
+            Light::new(MAX_LIGHT, 0)
+        );
+        assert_eq!(light_at(&chunks, 3, 10, 3), Light::new(MAX_LIGHT, 0));
+        assert_eq!(light_at(&chunks, 3, 9, 3), Light::new(0, 0));
+        assert_eq!(light_at(&chunks, 3, 0, 3), Light::new(0, 0));
+    }
+
+    #[test]
+    pub fn uniform_light_takes_no_memory() {
+        let registry = registry();
+        let mut chunks = HashMap::new();
+        load(&registry, &mut chunks, (0, 1, 0), Chunk::void());
+        load(&registry, &mut chunks, (0, 0, 0), Chunk::gen_flat(10));
+        load(
+            &registry,
+            &mut chunks,
+            (0, -1, 0),
+            Chunk::gen_flat(CHUNK_DIM),
+        );
+
+        let light = |chunks: &HashMap<ChunkIndex, Chunk>, i: (i32, i32, i32)| {
+            chunks[&i.into()].light.clone()
+        };
+        assert_eq!(
+            light(&chunks, (0, 1, 0)),
+            ChunkLight::Uniform(Light::new(MAX_LIGHT, 0))
+        );
+        assert_eq!(
+            light(&chunks, (0, -1, 0)),
+            ChunkLight::Uniform(Light::new(0, 0))
+        );
+        assert_eq!(light(&chunks, (0, 0, 0)).heap_usage(), CHUNK_N_VOXELS);
+
+        // A lamp in the dark needs the light of every voxel, until it's gone
+        let lamp = VoxelIndex::new(3, -5, 3);
+        let previous = set_voxel(&mut chunks, 3, -5, 3, LAMP);
+        LightEngine::new(&registry).voxel_changed(&mut chunks, lamp, previous);
+        assert_eq!(light(&chunks, (0, -1, 0)).heap_usage(), CHUNK_N_VOXELS);
+        let previous = set_voxel(&mut chunks, 3, -5, 3, VoxelType::GROUND);
+        LightEngine::new(&registry).voxel_changed(&mut chunks, lamp, previous);
+        assert_eq!(
+            light(&chunks, (0, -1, 0)),
+            ChunkLight::Uniform(Light::new(0, 0))
+        );
+    }
+
+    #[test]
+    pub fn sunlight_passes_through_sections() {
+        let registry = registry();
+        let mut chunks = HashMap::new();
+
+        // Open sections stacked on each other are lit all the way down
+        load(&registry, &mut chunks, (0, 1, 0), Chunk::void());
+        load(&registry, &mut chunks, (0, 0, 0), Chunk::void());
+        assert_eq!(light_at(&chunks, 3, 0, 3).sky(), MAX_LIGHT);
+
+        // A section below which was there first falls into the shadow of
+        // ground loaded above it
+        load(&registry, &mut chunks, (1, -1, 0), Chunk::void());
+        assert_eq!(light_at(&chunks, CHUNK_DIM + 3, -5, 3).sky(), MAX_LIGHT);
+        load(&registry, &mut chunks, (1, 0, 0), Chunk::gen_flat(10));
+        assert_eq!(light_at(&chunks, CHUNK_DIM + 3, -5, 3).sky(), 0);
+        assert_eq!(light_at(&chunks, CHUNK_DIM + 3, 10, 3).sky(), MAX_LIGHT);
+    }
+
+    #[test]
+    pub fn sunlight_spreads_under_a_roof() {
+        let registry = registry();
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::gen_flat(10);
+        for x in 0..CHUNK_DIM {
+            for z in 0..4 {
+                *chunk.voxel_mut(x, 14, z) = Voxel::new(VoxelType::GROUND);
+            }
+        }
+        // Glass lets the sun through
+        *chunk.voxel_mut(0, 14, 0) = Voxel::new(VoxelType(2));
+        load(&registry, &mut chunks, (0, 0, 0), chunk);
+
+        // The nearest sunlit column is at z = 4
+        assert_eq!(light_at(&chunks, 5, 12, 3).sky(), MAX_LIGHT - 1);
+        assert_eq!(light_at(&chunks, 5, 12, 1).sky(), MAX_LIGHT - 3);
+        assert_eq!(light_at(&chunks, 0, 12, 0).sky(), MAX_LIGHT);
+        assert_eq!(light_at(&chunks, 5, 15, 1).sky(), MAX_LIGHT);
+    }
+
+    #[test]
+    pub fn light_crosses_chunk_borders() {
+        let registry = registry();
+        let mut chunks = HashMap::new();
+
+        let mut roofed = Chunk::gen_flat(10);
+        roofed.iter_mut(|(_, y, _), voxel| {
+            if y == 14 {
+                *voxel = Voxel::new(VoxelType::GROUND);
+            }
+        });
+        let mut lit = roofed.clone();
+        *lit.voxel_mut(4, 12, 4) = Voxel::new(LAMP);
+        load(&registry, &mut chunks, (0, 0, 0), lit);
+
+        // Lamp light reaches the next chunk, once it's there
+        assert_eq!(light_at(&chunks, 2, 12, 4).block(), 12);
+        load(&registry, &mut chunks, (-1, 0, 0), roofed);
+        assert_eq!(light_at(&chunks, -1, 12, 4).block(), 9);
+
+        // Sunlight from the open chunk flows under the roof of the other one
+        load(&registry, &mut chunks, (0, 0, 1), Chunk::gen_flat(10));
+        assert_eq!(light_at(&chunks, 3, 12, CHUNK_DIM - 1).sky(), MAX_LIGHT - 1);
+        assert_eq!(light_at(&chunks, 3, 12, CHUNK_DIM - 3).sky(), MAX_LIGHT - 3);
+        assert_eq!(
+            light_at(&chunks, -1, 12, CHUNK_DIM - 1).sky(),
+            MAX_LIGHT - 2
+        );
+    }
+
+    #[test]
+    pub fn placing_and_breaking_voxels_updates_light() {
+        let registry = registry();
+        let mut chunks = HashMap::new();
+        load(&registry, &mut chunks, (0, 0, 0), Chunk::gen_flat(10));
+        load(&registry, &mut chunks, (1, 0, 0), Chunk::gen_flat(10));
+
+        // A lamp lights its surroundings, also across the border
+        let lamp = VoxelIndex::new(CHUNK_DIM - 2, 10, 3);
+        let previous = set_voxel(&mut chunks, CHUNK_DIM - 2, 10, 3, LAMP);
+        LightEngine::new(&registry).voxel_changed(&mut chunks, lamp, previous);
+        assert_eq!(light_at(&chunks, CHUNK_DIM - 2, 10, 3).block(), 14);
+        assert_eq!(light_at(&chunks, CHUNK_DIM - 2, 11, 3).block(), 13);
+        assert_eq!(light_at(&chunks, CHUNK_DIM + 1, 10, 3).block(), 11);
+
+        // and they go dark again when it's gone
+        let previous = set_voxel(&mut chunks, CHUNK_DIM - 2, 10, 3, VoxelType::VOID);
+        LightEngine::new(&registry).voxel_changed(&mut chunks, lamp, previous);
+        assert_eq!(light_at(&chunks, CHUNK_DIM - 2, 11, 3).block(), 0);
+        assert_eq!(light_at(&chunks, CHUNK_DIM + 1, 10, 3).block(), 0);
+
+        // A block in the sky casts a shadow down to the ground
+        let previous = set_voxel(&mut chunks, 3, 14, 3, VoxelType::GROUND);
+        LightEngine::new(&registry).voxel_changed(&mut chunks, VoxelIndex::new(3, 14, 3), previous);
+        assert_eq!(light_at(&chunks, 3, 15, 3).sky(), MAX_LIGHT);
+        assert_eq!(light_at(&chunks, 3, 14, 3).sky(), 0);
+        assert_eq!(light_at(&chunks, 3, 10, 3).sky(), MAX_LIGHT - 1);
+
+        let previous = set_voxel(&mut chunks, 3, 14, 3, VoxelType::VOID);
+        LightEngine::new(&registry).voxel_changed(&mut chunks, VoxelIndex::new(3, 14, 3), previous);
+        assert_eq!(light_at(&chunks, 3, 10, 3).sky(), MAX_LIGHT);
+
+        // Digging into the ground lets sunlight in
+        let previous = set_voxel(&mut chunks, 3, 9, 3, VoxelType::VOID);
+        LightEngine::new(&registry).voxel_changed(&mut chunks, VoxelIndex::new(3, 9, 3), previous);
+        assert_eq!(light_at(&chunks, 3, 9, 3).sky(), MAX_LIGHT);
+    }
+}
@@ -0,0 +1,521 @@
+use crate::world::chunk::{neighbour_slot, Chunk, CHUNK_DIM, NEIGHBOURHOOD};
+use crate::world::light::{Light, MAX_LIGHT};
+use crate::world::{registry, Voxel, VoxelType};
+
+use cgmath::Vector3;
+
+/// Vertex layout of chunk meshes, mirrored by the attributes set up in
+/// `ChunkRenderdata::upload`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkVertex {
+    pub position: Vector3<f32>,
+    /// Layer of the block texture array drawn on the face.
+    pub layer: u32,
+    /// Ambient occlusion, from 0 for a fully occluded corner to 1 for an open one.
+    pub ao: f32,
+    /// Sky and block light in front of the face, from 0 to 1.
+    pub light: [f32; 2],
+}
+
+impl ChunkVertex {
+    pub fn new(x: f32, y: f32, z: f32, layer: u32, ao: f32, light: Light) -> ChunkVertex {
+        ChunkVertex {
+            position: Vector3::new(x, y, z),
+            layer,
+            ao,
+            light: [
+                light.sky() as f32 / MAX_LIGHT as f32,
+                light.block() as f32 / MAX_LIGHT as f32,
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MeshStats {
+    /// Number of quads after merging.
+    pub quads: usize,
+    /// Number of visible voxel faces covered by those quads.
+    pub faces: usize,
+}
+
+/// CPU side geometry of a chunk, ready to be uploaded with
+/// `ChunkRenderdata::upload`. Building one doesn't touch OpenGL, so it can
+/// happen on any thread.
+#[derive(Debug, Default, Clone)]
+pub struct ChunkMesh {
+    pub vertices: Vec<ChunkVertex>,
+    pub indices: Vec<u32>,
+    /// Min and max corner of the geometry in chunk local coordinates,
+    /// `None` if the mesh is empty.
+    pub bounds: Option<(Vector3<i32>, Vector3<i32>)>,
+    pub stats: MeshStats,
+}
+
+/// The chunks around a chunk, indexed by `chunk::neighbour_slot`. Neighbours
+/// which aren't loaded are `None`, the centre slot is never used.
+#[derive(Default, Clone, Copy)]
+pub struct Neighbours<'a> {
+    pub chunks: [Option<&'a Chunk>; NEIGHBOURHOOD],
+}
+
+impl<'a> Neighbours<'a> {
+    // The chunk holding a position given in the local coordinates of the
+    // centre chunk, its offset from the centre and the position local to it.
+    fn locate(&self, chunk: &'a Chunk, p: [i32; 3]) -> (Option<&'a Chunk>, [i32; 3], [i32; 3]) {
+        let mut offset = [0; 3];
+        let mut local = p;
+        for axis in 0..3 {
+            offset[axis] = p[axis].div_euclid(CHUNK_DIM);
+            local[axis] -= offset[axis] * CHUNK_DIM;
+        }
+
+        let neighbour = match offset {
+            [0, 0, 0] => Some(chunk),
+            [x, y, z] => self.chunks[neighbour_slot(x, y, z)],
+        };
+        (neighbour, offset, local)
+    }
+
+    /// Looks up a voxel given in the local coordinates of the centre chunk,
+    /// which may lie at most one chunk outside of it on every axis.
+    pub fn voxel(&self, chunk: &'a Chunk, x: i32, y: i32, z: i32) -> Option<&'a Voxel> {
+        match self.locate(chunk, [x, y, z]) {
+            (Some(chunk), _, [x, y, z]) => Some(chunk.voxel(x, y, z)),
+            _ => None,
+        }
+    }
+
+    /// Looks up light like `voxel` does. Missing neighbours are dark, except
+    /// for the ones above the chunk, where the sky is open.
+    pub fn light(&self, chunk: &'a Chunk, x: i32, y: i32, z: i32) -> Light {
+        match self.locate(chunk, [x, y, z]) {
+            (Some(chunk), _, [x, y, z]) => chunk.light(x, y, z),
+            (None, [_, 1, _], _) => Light::new(MAX_LIGHT, 0),
+            (None, _, _) => Light::default(),
+        }
+    }
+}
+
+/// A visible voxel face in the meshing mask: its type, the light in front of
+/// it and the ambient occlusion level (0 to 3) of its corners, ordered (0, 0),
+/// (1, 0), (1, 1), (0, 1) along the mask's u and v axes. Only equal faces are
+/// merged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct MaskFace {
+    voxel_type: VoxelType,
+    light: Light,
+    ao: [u8; 4],
+}
+
+// Classic voxel corner occlusion from the two voxels beside a corner and the
+// one diagonal to it, all in the layer in front of the face.
+fn corner_ao(side1: bool, side2: bool, corner: bool) -> u8 {
+    if side1 && side2 {
+        0
+    } else {
+        3 - side1 as u8 - side2 as u8 - corner as u8
+    }
+}
+
+impl ChunkMesh {
+    /// Builds the visible surface of a chunk.
+    ///
+    /// Faces are only emitted where a solid voxel borders air or a transparent
+    /// voxel of another type, and coplanar faces of the same `VoxelType`, light
+    /// and ambient occlusion are merged into as few quads as possible.
+    /// Faces on the chunk border are culled and occluded against `neighbours`,
+    /// missing neighbours count as air.
+    pub fn build(chunk: &Chunk, neighbours: &Neighbours) -> ChunkMesh {
+        let mut mesh = ChunkMesh::default();
+
+        let registry = registry::get();
+        let voxel_type = |p: [i32; 3]| -> Option<VoxelType> {
+            neighbours
+                .voxel(chunk, p[0], p[1], p[2])
+                .map(|voxel| voxel.voxel_type)
+        };
+        // A face is hidden behind an opaque solid voxel, or one of its own type
+        let covers = |t: VoxelType, q: VoxelType| {
+            q == t || (registry.is_solid(q) && !registry.is_transparent(q))
+        };
+        let occludes = |p: [i32; 3]| {
+            voxel_type(p).map_or(false, |t| {
+                registry.is_solid(t) && !registry.is_transparent(t)
+            })
+        };
+
+        for d in 0..3 {
+            let u = (d + 1) % 3;
+            let v = (d + 2) % 3;
+            let mut mask: Vec<Option<MaskFace>> = vec![None; (CHUNK_DIM * CHUNK_DIM) as usize];
+            let mask_index = |i: i32, j: i32| (i + j * CHUNK_DIM) as usize;
+
+            for &backface in &[false, true] {
+                for slice in 0..CHUNK_DIM {
+                    for j in 0..CHUNK_DIM {
+                        for i in 0..CHUNK_DIM {
+                            let mut p = [0; 3];
+                            p[d] = slice;
+                            p[u] = i;
+                            p[v] = j;
+
+                            let mut q = p;
+                            q[d] += if backface { -1 } else { 1 };
+
+                            let visible = match (voxel_type(p), voxel_type(q)) {
+                                (Some(t), Some(q)) if registry.is_solid(t) && !covers(t, q) => {
+                                    Some(t)
+                                }
+                                (Some(t), None) if registry.is_solid(t) => Some(t),
+                                _ => None,
+                            };
+
+                            mask[mask_index(i, j)] = visible.map(|voxel_type| {
+                                let mut ao = [0; 4];
+                                let corners = [(-1, -1), (1, -1), (1, 1), (-1, 1)];
+                                for (n, (su, sv)) in corners.iter().enumerate() {
+                                    let mut side1 = q;
+                                    side1[u] += su;
+                                    let mut side2 = q;
+                                    side2[v] += sv;
+                                    let mut corner = side1;
+                                    corner[v] += sv;
+
+                                    ao[n] = corner_ao(
+                                        occludes(side1),
+                                        occludes(side2),
+                                        occludes(corner),
+                                    );
+                                }
+                                MaskFace {
+                                    voxel_type,
+                                    light: neighbours.light(chunk, q[0], q[1], q[2]),
+                                    ao,
+                                }
+                            });
+                        }
+                    }
+
+                    for j in 0..CHUNK_DIM {
+                        let mut i = 0;
+                        while i < CHUNK_DIM {
+                            let face = match mask[mask_index(i, j)] {
+                                Some(face) => face,
+                                None => {
+                                    i += 1;
+                                    continue;
+                                }
+                            };
+
+                            let mut w = 1;
+                            while i + w < CHUNK_DIM && mask[mask_index(i + w, j)] == Some(face) {
+                                w += 1;
+                            }
+
+                            let mut h = 1;
+                            'grow: while j + h < CHUNK_DIM {
+                                for k in 0..w {
+                                    if mask[mask_index(i + k, j + h)] != Some(face) {
+                                        break 'grow;
+                                    }
+                                }
+                                h += 1;
+                            }
+
+                            for l in 0..h {
+                                for k in 0..w {
+                                    mask[mask_index(i + k, j + l)] = None;
+                                }
+                            }
+
+                            let mut origin = [0; 3];
+                            origin[d] = if backface { slice } else { slice + 1 };
+                            origin[u] = i;
+                            origin[v] = j;
+
+                            let mut du = [0; 3];
+                            du[u] = w;
+                            let mut dv = [0; 3];
+                            dv[v] = h;
+
+                            let side = d * 2 + if backface { 0 } else { 1 };
+                            let layer = registry.texture_layer(face.voxel_type, side);
+                            mesh.push_quad(origin, du, dv, backface, layer, face.light, face.ao);
+
+                            i += w;
+                        }
+                    }
+                }
+            }
+        }
+
+        mesh
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    // Corners are wound counter-clockwise when seen from outside the voxel, so the
+    // normal derived in voxel.gs points away from the solid side.
+    fn push_quad(
+        &mut self,
+        origin: [i32; 3],
+        du: [i32; 3],
+        dv: [i32; 3],
+        backface: bool,
+        layer: u32,
+        light: Light,
+        ao: [u8; 4],
+    ) {
+        let corner = |a: i32, b: i32, ao: u8| {
+            ChunkVertex::new(
+                (origin[0] + a * du[0] + b * dv[0]) as f32,
+                (origin[1] + a * du[1] + b * dv[1]) as f32,
+                (origin[2] + a * du[2] + b * dv[2]) as f32,
+                layer,
+                ao as f32 / 3.0,
+                light,
+            )
+        };
+
+        let i = self.vertices.len() as u32;
+        let corners = if backface {
+            [
+                corner(0, 0, ao[0]),
+                corner(0, 1, ao[3]),
+                corner(1, 1, ao[2]),
+                corner(1, 0, ao[1]),
+            ]
+        } else {
+            [
+                corner(0, 0, ao[0]),
+                corner(1, 0, ao[1]),
+                corner(1, 1, ao[2]),
+                corner(0, 1, ao[3]),
+            ]
+        };
+        self.vertices.extend_from_slice(&corners);
+
+        // Split the quad along its darker diagonal, otherwise the occlusion is
+        // interpolated differently depending on the quad's orientation.
+        if ao[0] + ao[2] > ao[1] + ao[3] {
+            self.indices
+                .extend_from_slice(&[i + 1, i + 2, i + 3, i + 1, i + 3, i + 0]);
+        } else {
+            self.indices
+                .extend_from_slice(&[i + 0, i + 1, i + 2, i + 0, i + 2, i + 3]);
+        }
+
+        let min = Vector3::new(origin[0], origin[1], origin[2]);
+        let max = min + Vector3::new(du[0] + dv[0], du[1] + dv[1], du[2] + dv[2]);
+        self.bounds = Some(match self.bounds {
+            Some((lo, hi)) => (
+                Vector3::new(lo.x.min(min.x), lo.y.min(min.y), lo.z.min(min.z)),
+                Vector3::new(hi.x.max(max.x), hi.y.max(max.y), hi.z.max(max.z)),
+            ),
+            None => (min, max),
+        });
+
+        self.stats.quads += 1;
+        self.stats.faces += (du[0] + du[1] + du[2]) as usize * (dv[0] + dv[1] + dv[2]) as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::chunk::CHUNK_DIM;
+
+    fn ground() -> Voxel {
+        Voxel {
+            voxel_type: VoxelType::GROUND,
+        }
+    }
+
+    #[test]
+    pub fn empty_chunk_has_no_faces() {
+        let mesh = ChunkMesh::build(&Chunk::void(), &Neighbours::default());
+
+        assert!(mesh.is_empty());
+        assert_eq!(mesh.bounds, None);
+        assert_eq!(mesh.stats, MeshStats::default());
+    }
+
+    #[test]
+    pub fn single_voxel() {
+        let mut chunk = Chunk::void();
+        *chunk.voxel_mut(3, 4, 5) = ground();
+
+        let mesh = ChunkMesh::build(&chunk, &Neighbours::default());
+
+        assert_eq!(mesh.stats, MeshStats { quads: 6, faces: 6 });
+        assert_eq!(mesh.vertices.len(), 24);
+        assert_eq!(mesh.indices.len(), 36);
+        assert_eq!(
+            mesh.bounds,
+            Some((Vector3::new(3, 4, 5), Vector3::new(4, 5, 6)))
+        );
+    }
+
+    #[test]
+    pub fn adjacent_voxels_share_no_faces() {
+        let mut chunk = Chunk::void();
+        *chunk.voxel_mut(3, 4, 5) = ground();
+        *chunk.voxel_mut(4, 4, 5) = ground();
+
+        let mesh = ChunkMesh::build(&chunk, &Neighbours::default());
+
+        assert_eq!(
+            mesh.stats,
+            MeshStats {
+                quads: 6,
+                faces: 10
+            }
+        );
+    }
+
+    #[test]
+    pub fn flat_chunk_merges_into_one_quad_per_side() {
+        let ground = 10;
+        let mesh = ChunkMesh::build(&Chunk::gen_flat(ground), &Neighbours::default());
+
+        let top_and_bottom = 2 * CHUNK_DIM * CHUNK_DIM;
+        let sides = 4 * CHUNK_DIM * ground;
+        assert_eq!(
+            mesh.stats,
+            MeshStats {
+                quads: 6,
+                faces: (top_and_bottom + sides) as usize,
+            }
+        );
+    }
+
+    #[test]
+    pub fn border_faces_are_culled_against_neighbours() {
+        let chunk = Chunk::gen_flat(10);
+        let solid = Chunk::gen_flat(10);
+        let mut neighbours = Neighbours::default();
+        for &(x, z) in &[(-1, 0), (1, 0), (0, 1)] {
+            neighbours.chunks[neighbour_slot(x, 0, z)] = Some(&solid);
+        }
+
+        let mesh = ChunkMesh::build(&chunk, &neighbours);
+
+        // top, bottom and the side facing the missing neighbour
+        assert_eq!(mesh.stats.quads, 3);
+        assert_eq!(
+            mesh.stats.faces,
+            (2 * CHUNK_DIM * CHUNK_DIM + CHUNK_DIM * 10) as usize
+        );
+    }
+
+    #[test]
+    pub fn faces_point_away_from_solid_voxels() {
+        use cgmath::InnerSpace;
+
+        let mut chunk = Chunk::void();
+        *chunk.voxel_mut(3, 4, 5) = ground();
+        let centre = Vector3::new(3.5, 4.5, 5.5);
+
+        let mesh = ChunkMesh::build(&chunk, &Neighbours::default());
+
+        for triangle in mesh.indices.chunks(3) {
+            let a = mesh.vertices[triangle[0] as usize].position;
+            let b = mesh.vertices[triangle[1] as usize].position;
+            let c = mesh.vertices[triangle[2] as usize].position;
+
+            let normal = (b - a).cross(c - a);
+            assert!(normal.dot((a + b + c) / 3.0 - centre) > 0.0);
+        }
+    }
+
+    // Ambient occlusion at a corner of the horizontal quads lying at height y
+    fn top_ao(mesh: &ChunkMesh, x: f32, y: f32, z: f32) -> Vec<f32> {
+        mesh.vertices
+            .chunks(4)
+            .filter(|quad| quad.iter().all(|v| v.position.y == y))
+            .flatten()
+            .filter(|v| v.position == Vector3::new(x, y, z))
+            .map(|v| v.ao)
+            .collect()
+    }
+
+    #[test]
+    pub fn ambient_occlusion_darkens_corners_next_to_walls() {
+        let mut chunk = Chunk::gen_flat(10);
+        *chunk.voxel_mut(3, 10, 3) = ground();
+
+        let mesh = ChunkMesh::build(&chunk, &Neighbours::default());
+
+        let next_to_wall = top_ao(&mesh, 3.0, 10.0, 3.0);
+        assert!(!next_to_wall.is_empty());
+        assert!(next_to_wall.iter().all(|ao| *ao < 1.0));
+
+        let open = top_ao(&mesh, 0.0, 10.0, 0.0);
+        assert!(!open.is_empty());
+        assert!(open.iter().all(|ao| *ao == 1.0));
+    }
+
+    #[test]
+    pub fn ambient_occlusion_looks_into_neighbours() {
+        let chunk = Chunk::gen_flat(10);
+        let mut west = Chunk::gen_flat(10);
+        *west.voxel_mut(CHUNK_DIM - 1, 10, 4) = ground();
+        let mut north_west = Chunk::gen_flat(10);
+        *north_west.voxel_mut(CHUNK_DIM - 1, 10, CHUNK_DIM - 1) = ground();
+
+        let mut neighbours = Neighbours::default();
+        neighbours.chunks[neighbour_slot(-1, 0, 0)] = Some(&west);
+        neighbours.chunks[neighbour_slot(-1, 0, -1)] = Some(&north_west);
+        let mesh = ChunkMesh::build(&chunk, &neighbours);
+
+        for &(x, z) in &[(0.0, 4.0), (0.0, 5.0)] {
+            let ao = top_ao(&mesh, x, 10.0, z);
+            assert!(!ao.is_empty());
+            assert!(ao.iter().all(|ao| *ao < 1.0));
+        }
+
+        // Only the diagonal voxel touches this corner
+        assert_eq!(top_ao(&mesh, 0.0, 10.0, 0.0), vec![2.0 / 3.0]);
+    }
+
+    #[test]
+    pub fn quads_are_split_along_the_darker_diagonal() {
+        let mut chunk = Chunk::gen_flat(10);
+        *chunk.voxel_mut(3, 10, 3) = ground();
+        *chunk.voxel_mut(5, 10, 4) = ground();
+        *chunk.voxel_mut(5, 11, 4) = ground();
+
+        let mesh = ChunkMesh::build(&chunk, &Neighbours::default());
+
+        for quad in mesh.indices.chunks(6) {
+            let ao = |n: usize| mesh.vertices[quad[n] as usize].ao;
+            // The diagonal shared by both triangles is (0, 2), the other (1, 5)
+            assert!(ao(0) + ao(2) <= ao(1) + ao(5));
+        }
+    }
+
+    #[test]
+    pub fn faces_sample_the_light_in_front_of_them() {
+        let mut chunk = Chunk::gen_flat(10);
+        chunk.set_light(3, 10, 3, Light::new(MAX_LIGHT, 5));
+
+        let mesh = ChunkMesh::build(&chunk, &Neighbours::default());
+
+        let lit: Vec<&ChunkVertex> = mesh
+            .vertices
+            .iter()
+            .filter(|v| v.light != [0.0, 0.0])
+            .collect();
+        assert_eq!(lit.len(), 4);
+        for v in lit {
+            assert_eq!(v.light, [1.0, 5.0 / MAX_LIGHT as f32]);
+            assert_eq!(v.position.y, 10.0);
+            assert!(v.position.x >= 3.0 && v.position.x <= 4.0);
+            assert!(v.position.z >= 3.0 && v.position.z <= 4.0);
+        }
+    }
+}
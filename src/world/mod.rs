@@ -1,35 +1,62 @@
+pub mod biome;
+pub mod caves;
 pub mod chunk;
+pub mod edit;
+pub mod erosion;
+pub mod jobs;
+pub mod light;
+pub mod mesher;
+pub mod ores;
+pub mod palette;
+pub mod raycast;
+pub mod region;
+pub mod registry;
+pub mod save;
+pub mod stages;
+pub mod streaming;
+pub mod structures;
+pub mod superflat;
+pub mod terrain;
 pub mod voxel;
 
 pub mod gen;
 
+pub use biome::Biome;
 pub use chunk::Chunk;
+pub use light::Light;
+pub use mesher::ChunkMesh;
+pub use raycast::RaycastHit;
+pub use streaming::StreamingConfig;
+pub use terrain::TerrainPreset;
 pub use voxel::{Voxel, VoxelType};
 
-use cgmath::Vector2;
 use cgmath::Vector3;
 
-use cgmath::num_traits::Signed;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::io::prelude::*;
+use std::mem;
+use std::ptr;
+use std::sync::Arc;
+use std::thread;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct ChunkIndex(Vector2<i32>);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChunkIndex(Vector3<i32>);
 
-impl Into<ChunkIndex> for (i32, i32) {
+impl Into<ChunkIndex> for (i32, i32, i32) {
     fn into(self) -> ChunkIndex {
-        let (x, z) = self;
-        ChunkIndex(Vector2::new(x, z))
+        let (x, y, z) = self;
+        ChunkIndex(Vector3::new(x, y, z))
     }
 }
 
 impl ChunkIndex {
+    pub fn offset(self, x: i32, y: i32, z: i32) -> ChunkIndex {
+        ChunkIndex(self.0 + Vector3::new(x, y, z))
+    }
+
     pub fn chunk_origin(self) -> Vector3<i32> {
-        Vector3::new(
-            self.0.x * chunk::CHUNK_DIM as i32,
-            0,
-            self.0.y * chunk::CHUNK_DIM as i32,
-        )
+        self.0 * chunk::CHUNK_DIM
     }
 }
 
@@ -42,9 +69,10 @@ impl VoxelIndex {
     }
 
     pub fn chunk_index(&self) -> ChunkIndex {
-        ChunkIndex(Vector2::new(
-            ((self.0.x) / chunk::CHUNK_DIM as i32) - self.0.x.is_negative() as i32,
-            ((self.0.z) / chunk::CHUNK_DIM as i32) - self.0.z.is_negative() as i32,
+        ChunkIndex(Vector3::new(
+            self.0.x.div_euclid(chunk::CHUNK_DIM),
+            self.0.y.div_euclid(chunk::CHUNK_DIM),
+            self.0.z.div_euclid(chunk::CHUNK_DIM),
         ))
     }
 
@@ -54,9 +82,9 @@ impl VoxelIndex {
 
     pub fn from_world(world: cgmath::Point3<f32>) -> VoxelIndex {
         VoxelIndex(Vector3::new(
-            world.x as i32 - world.x.is_negative() as i32,
-            world.y as i32 - world.y.is_negative() as i32,
-            world.z as i32 - world.z.is_negative() as i32,
+            world.x.floor() as i32,
+            world.y.floor() as i32,
+            world.z.floor() as i32,
         ))
     }
 }
@@ -64,12 +92,100 @@ impl VoxelIndex {
 #[derive(Default, Debug, Clone, Copy)]
 pub struct ChunkRenderdata {
     pub vao: u32,
+    pub vbo: u32,
+    pub ebo: u32,
     pub indices_len: u32,
 }
 
 impl ChunkRenderdata {
-    pub fn from_vao_handle((vao, indices_len): (u32, u32)) -> ChunkRenderdata {
-        ChunkRenderdata { vao, indices_len }
+    /// Uploads a mesh to the GPU, reusing the buffers of a previous upload.
+    pub fn upload(&mut self, mesh: &ChunkMesh) {
+        unsafe {
+            if self.vao == 0 {
+                gl::CreateVertexArrays(1, &mut self.vao);
+                gl::CreateBuffers(1, &mut self.vbo);
+                gl::CreateBuffers(1, &mut self.ebo);
+
+                gl::BindVertexArray(self.vao);
+                gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
+                gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.ebo);
+
+                // Positions
+                gl::VertexAttribPointer(
+                    0,
+                    3,
+                    gl::FLOAT,
+                    0,
+                    mem::size_of::<mesher::ChunkVertex>() as i32,
+                    ptr::null(),
+                );
+                gl::EnableVertexAttribArray(0);
+
+                // Texture layers
+                gl::VertexAttribIPointer(
+                    1,
+                    1,
+                    gl::UNSIGNED_INT,
+                    mem::size_of::<mesher::ChunkVertex>() as i32,
+                    mem::size_of::<Vector3<f32>>() as *const gl::types::GLvoid,
+                );
+                gl::EnableVertexAttribArray(1);
+
+                // Ambient occlusion
+                gl::VertexAttribPointer(
+                    2,
+                    1,
+                    gl::FLOAT,
+                    0,
+                    mem::size_of::<mesher::ChunkVertex>() as i32,
+                    (mem::size_of::<Vector3<f32>>() + mem::size_of::<u32>())
+                        as *const gl::types::GLvoid,
+                );
+                gl::EnableVertexAttribArray(2);
+
+                // Sky and block light
+                gl::VertexAttribPointer(
+                    3,
+                    2,
+                    gl::FLOAT,
+                    0,
+                    mem::size_of::<mesher::ChunkVertex>() as i32,
+                    (mem::size_of::<Vector3<f32>>() + mem::size_of::<u32>() + mem::size_of::<f32>())
+                        as *const gl::types::GLvoid,
+                );
+                gl::EnableVertexAttribArray(3);
+
+                gl::BindVertexArray(0);
+            }
+
+            gl::NamedBufferData(
+                self.vbo,
+                (mem::size_of::<mesher::ChunkVertex>() * mesh.vertices.len()) as isize,
+                mesh.vertices.as_ptr() as *const gl::types::GLvoid,
+                gl::STATIC_DRAW,
+            );
+            gl::NamedBufferData(
+                self.ebo,
+                (mem::size_of::<u32>() * mesh.indices.len()) as isize,
+                mesh.indices.as_ptr() as *const gl::types::GLvoid,
+                gl::STATIC_DRAW,
+            );
+        }
+
+        self.indices_len = mesh.indices.len() as u32;
+    }
+
+    /// Frees the GPU buffers, leaving an empty renderdata behind.
+    pub fn delete(&mut self) {
+        if self.vao != 0 {
+            unsafe {
+                gl::DeleteVertexArrays(1, &self.vao);
+                gl::DeleteBuffers(1, &self.vbo);
+                gl::DeleteBuffers(1, &self.ebo);
+            }
+        }
+
+        *self = ChunkRenderdata::default();
     }
 }
 
@@ -127,10 +243,7 @@ impl WorldRenderer {
             .setUniform("projection", self.camera.get_projection_matrix());
         self.voxel_shader.setUniform("gTime", 0i32);
         self.voxel_shader.setUniform("chunkIndex", i.0);
-        self.voxel_shader.setUniform(
-            "chunkDims",
-            Vector2::new(chunk::CHUNK_DIM as i32, chunk::CHUNK_DIM as i32),
-        );
+        self.voxel_shader.setUniform("chunkDim", chunk::CHUNK_DIM);
 
         unsafe {
             gl::BindVertexArray(renderdata.vao);
@@ -145,61 +258,460 @@ impl WorldRenderer {
     }
 }
 
+/// Number of chunk meshes uploaded to the GPU per call to `World::update`
+/// unless configured otherwise.
+pub const DEFAULT_UPLOAD_BUDGET: usize = 8;
+
 pub struct World {
-    pub chunks: Vec<(ChunkIndex, Chunk, ChunkRenderdata)>,
-    generator: gen::WorldGenerator,
+    /// Shared with mesh jobs and the chunk writer, `chunk_mut` copies a chunk
+    /// that's still in use by one of them.
+    pub chunks: HashMap<ChunkIndex, (Arc<Chunk>, ChunkRenderdata)>,
+    generator: Arc<gen::WorldGenerator>,
+    workers: jobs::WorkerPool,
+    /// Chunks requested from the workers which haven't arrived yet.
+    generating: HashSet<ChunkIndex>,
+    /// Chunks with a mesh job in flight.
+    meshing: HashSet<ChunkIndex>,
+    /// Chunks with a mesh job in flight that were re-meshed on the main thread
+    /// in the meantime, their job results are outdated.
+    stale_meshes: HashSet<ChunkIndex>,
+    /// Finished meshes waiting for their GPU upload.
+    uploads: VecDeque<(ChunkIndex, ChunkMesh)>,
+    pub upload_budget: usize,
+    /// Arguments of the last `stream_around` call.
+    streamed_around: Option<(ChunkIndex, StreamingConfig)>,
+    /// Where edited chunks are saved, set by `load` and `save`.
+    store: Option<Arc<region::RegionStore>>,
+    /// Edited chunks that were unloaded, with the number of the writer batch
+    /// taking them into the save. They're dropped once it's written.
+    unsaved: HashMap<ChunkIndex, (u64, Arc<Chunk>)>,
+    writer: save::ChunkWriter,
+    /// Structure voxels loaded chunks spilled into their neighbours, by the
+    /// chunk they stand in. They're placed in neighbours generated later, and
+    /// kept until the chunk is unloaded, so a neighbour generated again after
+    /// being unloaded gets them too.
+    spilled: HashMap<ChunkIndex, Vec<(VoxelIndex, Voxel)>>,
 }
 
 impl World {
     pub fn empty() -> World {
+        World::with_seed(0)
+    }
+
+    /// An empty world with the builtin terrain generated from `seed`.
+    pub fn with_seed(seed: u64) -> World {
+        World::new(seed, TerrainPreset::default())
+    }
+
+    pub fn new(seed: u64, preset: TerrainPreset) -> World {
+        let generator = Arc::new(gen::WorldGenerator::new(seed, preset));
+        let n_workers = thread::available_parallelism()
+            .map(|n| n.get().saturating_sub(1))
+            .unwrap_or(1);
+
         World {
-            chunks: Vec::new(),
-            generator: gen::WorldGenerator::new(),
+            chunks: HashMap::new(),
+            workers: jobs::WorkerPool::new(generator.clone(), n_workers),
+            generator,
+            generating: HashSet::new(),
+            meshing: HashSet::new(),
+            stale_meshes: HashSet::new(),
+            uploads: VecDeque::new(),
+            upload_budget: DEFAULT_UPLOAD_BUDGET,
+            streamed_around: None,
+            store: None,
+            unsaved: HashMap::new(),
+            writer: save::ChunkWriter::new(),
+            spilled: HashMap::new(),
         }
     }
 
+    pub fn seed(&self) -> u64 {
+        self.generator.seed()
+    }
+
+    pub fn preset(&self) -> &TerrainPreset {
+        self.generator.preset()
+    }
+
+    /// Time the generator spent in each of its stages so far.
+    pub fn stage_timings(&self) -> Vec<stages::StageTiming> {
+        self.generator.stage_timings()
+    }
+
+    /// The biome the column of a voxel was generated as.
+    pub fn biome_at(&self, index: VoxelIndex) -> &Biome {
+        self.generator.biome_at(index)
+    }
+
     pub fn insert_chunk<C>(&mut self, i: C, chunk: Chunk)
     where
         C: Into<ChunkIndex>,
     {
-        self.chunks
-            .push((i.into(), chunk, ChunkRenderdata::default()));
+        let i = i.into();
+
+        if let Some((_, mut replaced)) = self
+            .chunks
+            .insert(i, (Arc::new(chunk), ChunkRenderdata::default()))
+        {
+            replaced.delete();
+        }
+
+        light::LightEngine::new(registry::get()).light_chunk(&mut self.chunks, i);
+
+        // Every neighbour gets re-meshed below, the border edits made while
+        // generating and lighting don't need to be propagated again.
+        if let Some(chunk) = self.chunk_mut(i) {
+            chunk.edited_neighbours = [false; chunk::NEIGHBOURHOOD];
+        }
+
+        for (x, y, z) in chunk::neighbour_offsets() {
+            self.mark_dirty(i.offset(x, y, z));
+        }
+    }
+
+    /// Inserts a generated chunk with the structures spilled into it by its
+    /// neighbours, and spills its own structures into them.
+    ///
+    /// Spilled voxels only go into empty space, and only once: chunks keep
+    /// track of the neighbours they got voxels from, in the save as well. A
+    /// neighbour generated again doesn't place them a second time, over
+    /// whatever the player did to them, but chunks edited before their
+    /// neighbour was generated still get its structures.
+    fn insert_generated(
+        &mut self,
+        i: ChunkIndex,
+        mut chunk: Chunk,
+        spilled: Vec<(VoxelIndex, Voxel)>,
+    ) {
+        for (x, y, z) in chunk::neighbour_offsets() {
+            let slot = chunk::neighbour_slot(x, y, z);
+            let voxels = match self.spilled.get(&i.offset(x, y, z)) {
+                Some(voxels) if !chunk.received[slot] => voxels,
+                _ => continue,
+            };
+            for (index, voxel) in voxels.iter().filter(|(index, _)| index.chunk_index() == i) {
+                let local = index.local_part();
+                if *chunk.voxel(local.x, local.y, local.z) == Voxel::void() {
+                    *chunk.voxel_mut(local.x, local.y, local.z) = *voxel;
+                }
+            }
+            chunk.received[slot] = true;
+        }
+        self.insert_chunk(i, chunk);
+
+        if spilled.is_empty() {
+            return;
+        }
+        for (x, y, z) in chunk::neighbour_offsets() {
+            let into = i.offset(x, y, z);
+            // Seen from the neighbour this chunk is the other way around
+            let slot = chunk::neighbour_slot(-x, -y, -z);
+            match self.chunk_mut(into) {
+                Some(neighbour) if !neighbour.received[slot] => neighbour.received[slot] = true,
+                _ => continue,
+            }
+            for (index, voxel) in spilled.iter() {
+                if index.chunk_index() == into && self.try_voxel(*index) == Some(Voxel::void()) {
+                    self.replace_voxel(*index, *voxel);
+                }
+            }
+        }
+        self.spilled.insert(i, spilled);
     }
 
+    /// Removes a chunk from the world and frees its GPU buffers. Edits are
+    /// kept in the save, if there is one, written by the next `update`.
+    pub fn unload_chunk(&mut self, i: ChunkIndex) {
+        let (chunk, mut renderdata) = match self.chunks.remove(&i) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        self.save_unloaded(i, chunk);
+        self.spilled.remove(&i);
+        renderdata.delete();
+        self.uploads.retain(|(index, _)| *index != i);
+
+        // The neighbours' faces towards this chunk are visible now, and
+        // their ambient occlusion along the shared border changed
+        for (x, y, z) in chunk::neighbour_offsets() {
+            self.mark_dirty(i.offset(x, y, z));
+        }
+    }
+
+    /// Marks the neighbours touching the edited borders of a chunk dirty.
+    fn propagate_edited_sides(&mut self, i: ChunkIndex) {
+        // Looked at first, chunks shared with a job aren't copied for nothing
+        match self.try_chunk(i) {
+            Some(chunk) if chunk.edited_neighbours.contains(&true) => {}
+            _ => return,
+        }
+        let chunk = self.chunk_mut(i).unwrap();
+        let edited = mem::replace(&mut chunk.edited_neighbours, [false; chunk::NEIGHBOURHOOD]);
+
+        for (x, y, z) in chunk::neighbour_offsets() {
+            if edited[chunk::neighbour_slot(x, y, z)] {
+                self.mark_dirty(i.offset(x, y, z));
+            }
+        }
+    }
+
+    fn mark_clean(&mut self, i: ChunkIndex) {
+        if self.try_chunk(i).map_or(false, |chunk| chunk.dirty) {
+            self.chunk_mut(i).unwrap().dirty = false;
+        }
+    }
+
+    fn mark_dirty(&mut self, i: ChunkIndex) {
+        if self.try_chunk(i).map_or(false, |chunk| !chunk.dirty) {
+            self.chunk_mut(i).unwrap().dirty = true;
+        }
+    }
+
+    /// Generates a chunk on the calling thread.
     pub fn gen_chunk<C>(&mut self, i: C)
     where
         C: Into<ChunkIndex> + Clone,
     {
-        let chunk = self.generator.gen_chunk(i.clone());
-        self.insert_chunk(i.into(), chunk);
+        let (chunk, spilled) = self.generator.gen_chunk(i.clone());
+        self.insert_generated(i.into(), chunk, spilled);
+    }
+
+    /// Queues a chunk for generation on the worker threads, it gets inserted
+    /// by a later `update` once it's done.
+    pub fn request_chunk<C>(&mut self, i: C)
+    where
+        C: Into<ChunkIndex>,
+    {
+        let i = i.into();
+        if self.is_loaded(i) || !self.generating.insert(i) {
+            return;
+        }
+
+        self.workers
+            .submit(jobs::Job::Generate(i, self.store.clone()));
+    }
+
+    /// Withdraws a `request_chunk` which hasn't been inserted yet.
+    pub fn cancel_chunk(&mut self, i: ChunkIndex) {
+        if self.generating.remove(&i) {
+            self.workers.cancel(i);
+        }
+    }
+
+    pub fn is_loaded(&self, i: ChunkIndex) -> bool {
+        self.chunks.contains_key(&i)
+    }
+
+    /// Collects finished jobs, queues dirty chunks for meshing and uploads at
+    /// most `upload_budget` meshes. Call this once per frame.
+    pub fn update(&mut self) {
+        while let Some(result) = self.workers.poll() {
+            match result {
+                jobs::JobResult::Generated(i, chunk, spilled) => {
+                    // Otherwise it was cancelled while being generated
+                    if self.generating.remove(&i) {
+                        // Edits not written yet are newer than what the
+                        // worker could read from the save
+                        let chunk = match self.unsaved.remove(&i) {
+                            Some((_, unsaved)) => {
+                                Arc::try_unwrap(unsaved).unwrap_or_else(|shared| (*shared).clone())
+                            }
+                            None => chunk,
+                        };
+                        self.insert_generated(i, chunk, spilled);
+                    }
+                }
+                jobs::JobResult::Meshed(i, mesh) => {
+                    self.meshing.remove(&i);
+                    if self.stale_meshes.remove(&i) {
+                        continue;
+                    }
+                    // A newer mesh supersedes one still waiting for upload
+                    self.uploads.retain(|(index, _)| *index != i);
+                    self.uploads.push_back((i, mesh));
+                }
+            }
+        }
+
+        self.send_unloaded();
+
+        let loaded: Vec<ChunkIndex> = self.chunks.keys().cloned().collect();
+        for i in loaded {
+            self.propagate_edited_sides(i);
+        }
+
+        let dirty: Vec<ChunkIndex> = self
+            .chunks
+            .iter()
+            .filter(|(_, (chunk, _))| chunk.dirty)
+            .map(|(i, _)| *i)
+            .collect();
+        for i in dirty {
+            // A chunk edited while its mesh job is running stays dirty and is
+            // picked up again once that job is done.
+            if self.meshing.contains(&i) {
+                continue;
+            }
+
+            // Cleaned before the job gets its share, so that doesn't copy it
+            self.mark_clean(i);
+
+            let chunk = self.chunks[&i].0.clone();
+            let mut neighbours = vec![None; chunk::NEIGHBOURHOOD];
+            for (x, y, z) in chunk::neighbour_offsets() {
+                neighbours[chunk::neighbour_slot(x, y, z)] = self
+                    .chunks
+                    .get(&i.offset(x, y, z))
+                    .map(|(chunk, _)| chunk.clone());
+            }
+            self.workers.submit(jobs::Job::Mesh(i, chunk, neighbours));
+
+            self.meshing.insert(i);
+        }
+
+        for _ in 0..self.upload_budget {
+            let (i, mesh) = match self.uploads.pop_front() {
+                Some(upload) => upload,
+                None => break,
+            };
+
+            if let Some((_, renderdata)) = self.chunks.get_mut(&i) {
+                renderdata.upload(&mesh);
+            }
+        }
+    }
+
+    /// Meshes and uploads the chunk of an edited voxel and its dirty
+    /// neighbours on the calling thread, so the edit shows up this frame
+    /// instead of waiting for the workers.
+    pub fn remesh_edited(&mut self, index: VoxelIndex) {
+        let i = index.chunk_index();
+        let mut affected = vec![i];
+        for (x, y, z) in chunk::neighbour_offsets() {
+            affected.push(i.offset(x, y, z));
+        }
+
+        for i in affected {
+            match self.chunks.get(&i) {
+                Some((chunk, _)) if chunk.dirty => {}
+                _ => continue,
+            }
+
+            let mesh = ChunkMesh::build(self.chunk(i), &self.neighbours(i));
+            if self.meshing.contains(&i) {
+                self.stale_meshes.insert(i);
+            }
+            self.uploads.retain(|(index, _)| *index != i);
+
+            let (_, renderdata) = self.chunks.get_mut(&i).unwrap();
+            renderdata.upload(&mesh);
+            self.mark_clean(i);
+        }
     }
 
     pub fn voxel_from_world(&self, world: cgmath::Point3<f32>) -> VoxelIndex {
-        VoxelIndex(Vector3::new(
-            world.x as i32 - world.x.is_negative() as i32,
-            world.y as i32 - world.y.is_negative() as i32,
-            world.z as i32 - world.z.is_negative() as i32,
-        ))
+        VoxelIndex::from_world(world)
+    }
+
+    /// Returns `None` if the chunk isn't loaded.
+    pub fn try_chunk(&self, i: ChunkIndex) -> Option<&Chunk> {
+        self.chunks.get(&i).map(|(chunk, _)| &**chunk)
     }
 
-    pub fn chunk(&self, chunkIndex: ChunkIndex) -> &Chunk {
-        for (index, chunk, _) in self.chunks.iter() {
-            if *index == chunkIndex {
-                return chunk;
+    /// Copies the chunk first if a job still holds on to it. Returns `None` if
+    /// the chunk isn't loaded.
+    fn chunk_mut(&mut self, i: ChunkIndex) -> Option<&mut Chunk> {
+        self.chunks
+            .get_mut(&i)
+            .map(|(chunk, _)| Arc::make_mut(chunk))
+    }
+
+    pub fn chunk(&self, i: ChunkIndex) -> &Chunk {
+        match self.try_chunk(i) {
+            Some(chunk) => chunk,
+            None => panic!("Chunk {:?} is not loaded", i),
+        }
+    }
+
+    /// Number of loaded chunks and the bytes their voxels and light take up.
+    pub fn chunk_memory(&self) -> (usize, usize) {
+        let bytes = self
+            .chunks
+            .values()
+            .map(|(chunk, _)| chunk.memory_usage())
+            .sum();
+        (self.chunks.len(), bytes)
+    }
+
+    /// Number of loaded chunks within `radius` chunks of `center`, and how
+    /// many voxels of every ore in the block registry they hold.
+    pub fn ore_counts(&self, center: ChunkIndex, radius: i32) -> (usize, Vec<(VoxelType, usize)>) {
+        let mut counts: Vec<(VoxelType, usize)> =
+            registry::get().ores().map(|(ore, _)| (ore, 0)).collect();
+        let mut n_chunks = 0;
+        for x in -radius..=radius {
+            for y in -radius..=radius {
+                for z in -radius..=radius {
+                    let chunk = match self.try_chunk(center.offset(x, y, z)) {
+                        Some(chunk) => chunk,
+                        None => continue,
+                    };
+                    n_chunks += 1;
+
+                    // Most chunks have no ore at all
+                    let palette = chunk.voxels.palette();
+                    if !counts
+                        .iter()
+                        .any(|(ore, _)| palette.iter().any(|v| v.voxel_type == *ore))
+                    {
+                        continue;
+                    }
+                    for voxel in chunk.voxels.to_vec() {
+                        if let Some((_, count)) =
+                            counts.iter_mut().find(|(ore, _)| *ore == voxel.voxel_type)
+                        {
+                            *count += 1;
+                        }
+                    }
+                }
             }
         }
+        (n_chunks, counts)
+    }
 
-        panic!("God help us")
+    pub fn neighbours(&self, i: ChunkIndex) -> mesher::Neighbours<'_> {
+        let mut neighbours = mesher::Neighbours::default();
+        for (x, y, z) in chunk::neighbour_offsets() {
+            neighbours.chunks[chunk::neighbour_slot(x, y, z)] = self.try_chunk(i.offset(x, y, z));
+        }
+        neighbours
     }
 
-    pub fn voxel(&self, index: VoxelIndex) -> Voxel {
+    /// Returns `None` if the voxel's chunk isn't loaded.
+    pub fn try_voxel(&self, index: VoxelIndex) -> Option<Voxel> {
+        let chunk = self.try_chunk(index.chunk_index())?;
         let i = index.local_part();
-        *self
-            .chunk(index.chunk_index())
-            .voxel(i.x as i32, i.y as i32, i.z as i32)
+
+        Some(*chunk.voxel(i.x, i.y, i.z))
     }
 
-    pub fn render(&mut self, renderer: &WorldRenderer) {
+    pub fn voxel(&self, index: VoxelIndex) -> Voxel {
+        match self.try_voxel(index) {
+            Some(voxel) => voxel,
+            None => panic!("Chunk {:?} is not loaded", index.chunk_index()),
+        }
+    }
+
+    /// Whether a voxel blocks movement. Unloaded chunks count as solid, so
+    /// nothing can fall into terrain which hasn't arrived yet.
+    pub fn is_blocking(&self, index: VoxelIndex) -> bool {
+        self.try_voxel(index)
+            .map_or(true, |voxel| voxel.has_collision())
+    }
+
+    pub fn render(&self, renderer: &WorldRenderer) {
         unsafe {
             gl::Enable(gl::DEPTH_TEST);
             gl::DepthFunc(gl::LESS);
@@ -207,13 +719,10 @@ impl World {
             gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
         }
 
-        for (i, chunk, renderdata) in &mut self.chunks {
-            if chunk.dirty {
-                *renderdata = ChunkRenderdata::from_vao_handle(chunk.gen_vertex_array());
-                chunk.dirty = false;
+        for (i, (_, renderdata)) in &self.chunks {
+            if renderdata.indices_len > 0 {
+                renderer.draw_chunk(*i, *renderdata);
             }
-
-            renderer.draw_chunk(*i, *renderdata);
         }
     }
 }
@@ -238,27 +747,35 @@ mod tests {
         let samples = [
             (
                 VoxelIndex(Vector3::new(0, 0, 0)),
-                ChunkIndex(Vector2::new(0, 0)),
+                ChunkIndex(Vector3::new(0, 0, 0)),
             ),
             (
                 VoxelIndex(Vector3::new(-1, 0, -1)),
-                ChunkIndex(Vector2::new(-1, -1)),
+                ChunkIndex(Vector3::new(-1, 0, -1)),
             ),
             (
                 VoxelIndex(Vector3::new(chunk::CHUNK_DIM as i32, 0, -1)),
-                ChunkIndex(Vector2::new(1, -1)),
+                ChunkIndex(Vector3::new(1, 0, -1)),
             ),
             (
                 VoxelIndex(Vector3::new(chunk::CHUNK_DIM as i32 * 4, 0, 0)),
-                ChunkIndex(Vector2::new(4, 0)),
+                ChunkIndex(Vector3::new(4, 0, 0)),
             ),
             (
                 VoxelIndex(Vector3::new(chunk::CHUNK_DIM as i32 * -4, 0, 0)),
-                ChunkIndex(Vector2::new(-5, 0)),
+                ChunkIndex(Vector3::new(-4, 0, 0)),
+            ),
+            (
+                VoxelIndex(Vector3::new(0, 0, chunk::CHUNK_DIM as i32 * -3 - 1)),
+                ChunkIndex(Vector3::new(0, 0, -4)),
             ),
             (
-                VoxelIndex(Vector3::new(0, 0, chunk::CHUNK_DIM as i32 * -3)),
-                ChunkIndex(Vector2::new(0, -4)),
+                VoxelIndex(Vector3::new(0, chunk::CHUNK_DIM as i32 * 2 + 3, 0)),
+                ChunkIndex(Vector3::new(0, 2, 0)),
+            ),
+            (
+                VoxelIndex(Vector3::new(0, -1, 0)),
+                ChunkIndex(Vector3::new(0, -1, 0)),
             ),
         ];
 
@@ -276,6 +793,10 @@ mod tests {
                 VoxelIndex(Vector3::new(-3, 2, -4)),
                 Vector3::new(chunk::CHUNK_DIM as i32 - 3, 2, chunk::CHUNK_DIM as i32 - 4),
             ),
+            (
+                VoxelIndex(Vector3::new(-chunk::CHUNK_DIM, -1, 0)),
+                Vector3::new(0, chunk::CHUNK_DIM as i32 - 1, 0),
+            ),
             (VoxelIndex(Vector3::new(0, 0, 0)), Vector3::new(0, 0, 0)),
         ];
 
@@ -292,6 +813,7 @@ mod tests {
             (Point3::new(0.0, 0.0, 0.0), VoxelIndex::new(0, 0, 0)),
             (Point3::new(1.2, 5.3, 6.4), VoxelIndex::new(1, 5, 6)),
             (Point3::new(-1.2, -5.7, -2.9), VoxelIndex::new(-2, -6, -3)),
+            (Point3::new(-1.0, 2.0, -0.5), VoxelIndex::new(-1, 2, -1)),
             (
                 Point3::new(
                     chunk::CHUNK_DIM as f32 * 3.0 + 2.0,
@@ -310,4 +832,350 @@ mod tests {
             assert_eq!(VoxelIndex::from_world(*sample), *predicate);
         }
     }
+
+    #[test]
+    pub fn lookups_in_unloaded_chunks() {
+        let mut world = World::empty();
+        world.insert_chunk((0, 0, 0), Chunk::gen_flat(10));
+
+        assert!(world.try_chunk((0, 0, 0).into()).is_some());
+        assert!(world.try_chunk((1, 0, 0).into()).is_none());
+
+        assert!(world
+            .try_voxel(VoxelIndex::new(1, 5, 1))
+            .unwrap()
+            .is_solid());
+        assert!(world.try_voxel(VoxelIndex::new(1, 500, 1)).is_none());
+        assert!(world.try_voxel(VoxelIndex::new(1, -1, 1)).is_none());
+        assert!(world.try_voxel(VoxelIndex::new(-1, 5, 1)).is_none());
+
+        assert!(world.is_blocking(VoxelIndex::new(1, 5, 1)));
+        assert!(!world.is_blocking(VoxelIndex::new(1, 15, 1)));
+        assert!(world.is_blocking(VoxelIndex::new(-1, 15, 1)));
+    }
+
+    #[test]
+    pub fn set_voxel_marks_chunks_dirty() {
+        let mut world = World::empty();
+        for x in -1..=1 {
+            world.insert_chunk((x, 0, 0), Chunk::gen_flat(10));
+        }
+        for (chunk, _) in world.chunks.values_mut() {
+            Arc::make_mut(chunk).dirty = false;
+        }
+
+        let ground = world.voxel(VoxelIndex::new(3, 5, 3));
+        let previous = world.set_voxel(VoxelIndex::new(3, 5, 3), Voxel::void());
+        assert_eq!(previous, Some(ground));
+        assert_eq!(world.voxel(VoxelIndex::new(3, 5, 3)), Voxel::void());
+        assert!(world.chunk((0, 0, 0).into()).dirty);
+        assert!(!world.chunk((-1, 0, 0).into()).dirty);
+
+        // On the border to chunk (-1, 0, 0)
+        world.set_voxel(VoxelIndex::new(0, 5, 3), Voxel::void());
+        assert!(world.chunk((-1, 0, 0).into()).dirty);
+        assert!(!world.chunk((1, 0, 0).into()).dirty);
+
+        assert_eq!(world.set_voxel(VoxelIndex::new(0, 500, 3), ground), None);
+        assert_eq!(world.set_voxel(VoxelIndex::new(50, 5, 3), ground), None);
+    }
+
+    #[test]
+    pub fn fill_reports_replaced_voxels() {
+        let mut world = World::empty();
+        world.insert_chunk((0, 0, 0), Chunk::gen_flat(10));
+
+        let replaced = world.fill(
+            VoxelIndex::new(1, 8, 1),
+            VoxelIndex::new(2, 11, 2),
+            Voxel::void(),
+        );
+        // Only y = 8 and 9 held ground
+        assert_eq!(replaced.len(), 2 * 2 * 2);
+
+        world.set_voxels(replaced);
+        assert!(world.voxel(VoxelIndex::new(2, 9, 2)).is_solid());
+        assert!(!world.voxel(VoxelIndex::new(2, 10, 2)).is_solid());
+    }
+
+    #[test]
+    pub fn saved_worlds_keep_their_edits() {
+        let dir = std::env::temp_dir().join(format!("rs-tri-save-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut world = World::with_seed(77);
+        world.insert_chunk((0, 0, 0), Chunk::gen_flat(10));
+        world.insert_chunk((1, 0, 0), Chunk::gen_flat(10));
+        world.insert_chunk((2, 0, 0), Chunk::gen_flat(10));
+        world.set_voxel(VoxelIndex::new(3, 12, 3), Voxel::new(VoxelType::GROUND));
+        world.save(&dir).unwrap();
+
+        // Edits of chunks unloaded after saving end up in the save as well
+        let far = VoxelIndex::new(chunk::CHUNK_DIM * 2 + 3, 12, 3);
+        world.set_voxel(far, Voxel::new(VoxelType::GROUND));
+        world.unload_chunk((2, 0, 0).into());
+        world.flush_unloaded();
+
+        let store = world.store.clone().unwrap();
+        assert!(store.read_chunk((0, 0, 0).into()).unwrap().is_some());
+        assert!(store.read_chunk((1, 0, 0).into()).unwrap().is_none());
+        assert!(store.read_chunk((2, 0, 0).into()).unwrap().is_some());
+
+        let mut loaded = World::load(&dir).unwrap();
+        // There's no GL context to upload meshes to
+        loaded.upload_budget = 0;
+        assert_eq!(loaded.seed(), 77);
+        for x in 0..3 {
+            loaded.request_chunk((x, 0, 0));
+        }
+        let start = std::time::Instant::now();
+        while (0..3).any(|x| !loaded.is_loaded((x, 0, 0).into())) {
+            assert!(start.elapsed().as_secs() < 10, "Chunks didn't arrive");
+            loaded.update();
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(loaded.voxel(VoxelIndex::new(3, 12, 3)).is_solid());
+        assert!(loaded.voxel(far).is_solid());
+        assert!(loaded.chunk((0, 0, 0).into()).modified);
+        assert!(!loaded.chunk((1, 0, 0).into()).modified);
+
+        // A chunk loaded again before its edits are written gets them too
+        let edit = VoxelIndex::new(chunk::CHUNK_DIM + 3, 12, 3);
+        loaded.set_voxel(edit, Voxel::new(VoxelType::GROUND));
+        loaded.unload_chunk((1, 0, 0).into());
+        loaded.request_chunk((1, 0, 0));
+        while !loaded.is_loaded((1, 0, 0).into()) {
+            assert!(start.elapsed().as_secs() < 10, "Chunk didn't arrive");
+            loaded.update();
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(loaded.voxel(edit).is_solid());
+        drop(loaded);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(World::load(&dir).is_err());
+    }
+
+    #[test]
+    pub fn raycast_down_onto_flat_ground() {
+        let mut world = World::empty();
+        world.insert_chunk((0, 0, 0), Chunk::gen_flat(10));
+        world.insert_chunk((0, 1, 0), Chunk::void());
+
+        let hit = world
+            .raycast(
+                Point3::new(2.5, 20.0, 3.5),
+                Vector3::new(0.0, -1.0, 0.0),
+                50.0,
+            )
+            .unwrap();
+
+        assert_eq!(hit.voxel, VoxelIndex::new(2, 9, 3));
+        assert_eq!(hit.normal, Vector3::new(0, 1, 0));
+        assert_eq!(hit.distance, 10.0);
+        assert_eq!(hit.previous, Some(VoxelIndex::new(2, 10, 3)));
+
+        assert_eq!(
+            world.raycast(
+                Point3::new(2.5, 20.0, 3.5),
+                Vector3::new(0.0, -1.0, 0.0),
+                9.0
+            ),
+            None
+        );
+    }
+
+    #[test]
+    pub fn raycast_across_chunks() {
+        let mut world = World::empty();
+        world.insert_chunk((0, 0, 0), Chunk::void());
+        world.insert_chunk((1, 0, 0), Chunk::void());
+        world.insert_chunk((2, 0, 0), Chunk::void());
+
+        let wall = VoxelIndex::new(chunk::CHUNK_DIM * 2 + 1, 5, 2);
+        world.set_voxel(
+            wall,
+            Voxel {
+                voxel_type: VoxelType::GROUND,
+            },
+        );
+
+        let hit = world
+            .raycast(
+                Point3::new(0.5, 5.5, 2.5),
+                Vector3::new(1.0, 0.0, 0.0),
+                100.0,
+            )
+            .unwrap();
+        assert_eq!(hit.voxel, wall);
+        assert_eq!(hit.normal, Vector3::new(-1, 0, 0));
+        assert_eq!(
+            hit.previous,
+            Some(VoxelIndex::new(chunk::CHUNK_DIM * 2, 5, 2))
+        );
+
+        // Chunk (-1, 0, 0) isn't loaded
+        assert_eq!(
+            world.raycast(
+                Point3::new(0.5, 5.5, 2.5),
+                Vector3::new(-1.0, 0.0, 0.0),
+                100.0
+            ),
+            None
+        );
+    }
+
+    #[test]
+    pub fn raycast_from_inside_a_voxel() {
+        let mut world = World::empty();
+        world.insert_chunk((0, 0, 0), Chunk::gen_flat(10));
+
+        let hit = world
+            .raycast(
+                Point3::new(2.5, 5.5, 3.5),
+                Vector3::new(1.0, 1.0, 0.0),
+                50.0,
+            )
+            .unwrap();
+        assert_eq!(hit.voxel, VoxelIndex::new(2, 5, 3));
+        assert_eq!(hit.normal, Vector3::new(0, 0, 0));
+        assert_eq!(hit.distance, 0.0);
+        assert_eq!(hit.previous, None);
+    }
+
+    #[test]
+    pub fn structures_are_not_cut_at_chunk_seams() {
+        let preset = TerrainPreset::from_json(
+            r#"{ "biomes": [{
+                "name": "forest", "temperature": 0, "humidity": 0,
+                "height": { "type": "constant", "value": 12.5 },
+                "structures": [{ "type": "tree", "chance": 0.05 }]
+            }] }"#,
+        )
+        .unwrap();
+        let mut chunks = Vec::new();
+        for x in -1..=1 {
+            for y in 0..=1 {
+                for z in -1..=1 {
+                    chunks.push((x, y, z));
+                }
+            }
+        }
+
+        let mut forward = World::new(3, preset.clone());
+        for i in chunks.iter() {
+            forward.gen_chunk(*i);
+        }
+        let mut backward = World::new(3, preset);
+        for i in chunks.iter().rev() {
+            backward.gen_chunk(*i);
+        }
+        let same = |a: &World, b: &World| {
+            chunks.iter().all(|i| {
+                let i: ChunkIndex = (*i).into();
+                a.chunk(i).voxels.to_vec() == b.chunk(i).voxels.to_vec()
+            })
+        };
+        assert!(same(&forward, &backward));
+        // Tree tops reach into the chunks above the ground
+        assert!(!forward.chunk((0, 1, 0).into()).voxels.is_uniform());
+
+        // A neighbour generated again gets its parts of the trees back
+        forward.unload_chunk((0, 1, 0).into());
+        forward.unload_chunk((1, 0, 0).into());
+        forward.gen_chunk((1, 0, 0));
+        forward.gen_chunk((0, 1, 0));
+        assert!(same(&forward, &backward));
+    }
+
+    #[test]
+    pub fn edits_to_spilled_voxels_are_kept() {
+        let preset = TerrainPreset::from_json(
+            r#"{ "biomes": [{
+                "name": "forest", "temperature": 0, "humidity": 0,
+                "height": { "type": "constant", "value": 12.5 },
+                "structures": [{ "type": "tree", "chance": 0.05 }]
+            }] }"#,
+        )
+        .unwrap();
+        let mut world = World::new(3, preset);
+        world.gen_chunk((0, 0, 0));
+        world.gen_chunk((0, 1, 0));
+
+        // A tree top the chunk below spilled into the one above
+        let above: ChunkIndex = (0, 1, 0).into();
+        let (leaf, voxel) = world.spilled[&(0, 0, 0).into()]
+            .iter()
+            .find(|(index, _)| index.chunk_index() == above)
+            .cloned()
+            .unwrap();
+        assert_eq!(world.voxel(leaf), voxel);
+        world.set_voxel(leaf, Voxel::void());
+
+        // Generating the chunk below again doesn't grow it back
+        world.unload_chunk((0, 0, 0).into());
+        world.gen_chunk((0, 0, 0));
+        assert_eq!(world.voxel(leaf), Voxel::void());
+    }
+
+    #[test]
+    pub fn structures_reach_into_chunks_edited_first() {
+        let dir = std::env::temp_dir().join(format!("rs-tri-spilled-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let preset = TerrainPreset::from_json(
+            r#"{ "biomes": [{
+                "name": "forest", "temperature": 0, "humidity": 0,
+                "height": { "type": "constant", "value": 12.5 },
+                "structures": [{ "type": "tree", "chance": 0.05 }]
+            }] }"#,
+        )
+        .unwrap();
+
+        // The chunk above is edited and saved before the one below, whose
+        // trees reach into it, was ever generated
+        let above: ChunkIndex = (0, 1, 0).into();
+        let mut world = World::new(3, preset);
+        let (_, spilled) = world.generator.gen_chunk((0, 0, 0));
+        let leaves: Vec<(VoxelIndex, Voxel)> = spilled
+            .into_iter()
+            .filter(|(index, _)| index.chunk_index() == above)
+            .collect();
+        let (edited, _) = leaves[0];
+        world.gen_chunk(above);
+        world.set_voxel(edited, Voxel::new(VoxelType::GROUND));
+        world.save(&dir).unwrap();
+        drop(world);
+
+        let mut loaded = World::load(&dir).unwrap();
+        loaded.upload_budget = 0;
+        loaded.request_chunk(above);
+        let start = std::time::Instant::now();
+        while !loaded.is_loaded(above) {
+            assert!(start.elapsed().as_secs() < 10, "Chunk didn't arrive");
+            loaded.update();
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(loaded.chunk(above).modified);
+
+        // The trees grow into it around the edit
+        loaded.gen_chunk((0, 0, 0));
+        assert_eq!(loaded.voxel(edited), Voxel::new(VoxelType::GROUND));
+        for (leaf, voxel) in &leaves[1..] {
+            assert_eq!(loaded.voxel(*leaf), *voxel);
+        }
+
+        // and only once, also after saving again
+        loaded.set_voxel(leaves[1].0, Voxel::void());
+        loaded.save(&dir).unwrap();
+        loaded.unload_chunk((0, 0, 0).into());
+        loaded.unload_chunk(above);
+        loaded.flush_unloaded();
+        let saved = loaded.store.as_ref().unwrap().read_chunk(above).unwrap();
+        loaded.insert_chunk(above, saved.unwrap());
+        loaded.gen_chunk((0, 0, 0));
+        assert_eq!(loaded.voxel(leaves[1].0), Voxel::void());
+        drop(loaded);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
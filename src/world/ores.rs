@@ -0,0 +1,342 @@
+use crate::world::chunk::{self, CHUNK_DIM};
+use crate::world::gen::{column_hash, roll, sub_seed};
+use crate::world::registry::{self, parse_ore, BlockRegistry, OreRule};
+use crate::world::stages::{GenerationStage, StageContext};
+use crate::world::{ChunkIndex, Voxel, VoxelIndex, VoxelType};
+
+use cgmath::Vector3;
+
+/// A stream of random numbers, SplitMix64.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: i32) -> i32 {
+        (self.next() % n as u64) as i32
+    }
+}
+
+/// Replaces host voxels with veins of the ores in the block registry.
+///
+/// The veins starting in a chunk only depend on the seed, the chunk and the
+/// ore, and they're never longer than a chunk. So every chunk places the
+/// parts of the veins of its neighbours that reach into it, and veins run on
+/// across chunk borders no matter which chunk is generated first.
+pub struct OreStage {
+    seed: u32,
+    ores: Vec<(VoxelType, OreRule)>,
+}
+
+impl OreStage {
+    pub fn new(seed: u64, ores: Vec<(VoxelType, OreRule)>) -> OreStage {
+        OreStage {
+            seed: sub_seed(seed, "ores"),
+            ores,
+        }
+    }
+
+    /// Builds the stage from the `ores` of its preset entry, see
+    /// `store_rules`, or with the ores of the installed block registry if the
+    /// entry has none.
+    pub fn build(entry: &serde_json::Value, seed: u64) -> Result<Box<dyn GenerationStage>, String> {
+        let blocks = registry::get();
+        let ores: Vec<_> = match &entry["ores"] {
+            serde_json::Value::Null => blocks
+                .ores()
+                .map(|(voxel_type, ore)| (voxel_type, ore.clone()))
+                .collect(),
+            ores => ores
+                .as_array()
+                .ok_or("ores needs to be a list of ore rules")?
+                .iter()
+                .map(|ore| {
+                    let find = |key: &str| {
+                        let name = ore[key]
+                            .as_str()
+                            .ok_or_else(|| format!("An ore needs a {} block", key))?;
+                        blocks
+                            .find(name)
+                            .ok_or_else(|| format!("Unknown block {} in ores", name))
+                    };
+                    let voxel_type = find("block")?;
+                    let rule = OreRule {
+                        host: find("host")?,
+                        ..parse_ore(ore, ore["block"].as_str().unwrap_or_default())?
+                    };
+                    Ok((voxel_type, rule))
+                })
+                .collect::<Result<_, String>>()?,
+        };
+        Ok(Box::new(OreStage::new(seed, ores)))
+    }
+
+    /// World positions of the veins of an ore starting in a chunk. They can
+    /// reach out of the chunk and its height range, and overlap.
+    fn veins(&self, voxel_type: VoxelType, ore: &OreRule, i: ChunkIndex) -> Vec<Vector3<i32>> {
+        let c = i.0;
+        let hash = column_hash(self.seed ^ voxel_type.0 as u32, c.x, c.z);
+        let mut random = Random(hash ^ (c.y as u32 as u64).rotate_left(32));
+
+        // Whole veins per chunk, and maybe one more
+        let mut n_veins = ore.frequency.floor() as u32;
+        if roll(random.next()) < ore.frequency.fract() {
+            n_veins += 1;
+        }
+
+        let origin = i.chunk_origin();
+        let mut voxels = Vec::new();
+        for _ in 0..n_veins {
+            let mut at = origin
+                + Vector3::new(
+                    random.below(CHUNK_DIM),
+                    random.below(CHUNK_DIM),
+                    random.below(CHUNK_DIM),
+                );
+            for _ in 0..ore.vein_size {
+                voxels.push(at);
+                let step = if random.next() & 1 == 0 { -1 } else { 1 };
+                at[random.below(3) as usize] += step;
+            }
+        }
+        voxels
+    }
+}
+
+/// Writes the ore rules of the installed block registry into the `ores`
+/// stages of a preset that don't have their own, like the rest of the
+/// terrain they're stored with the world that way.
+pub fn store_rules(preset: &mut serde_json::Value) {
+    let stages = match preset["stages"].as_array_mut() {
+        Some(stages) => stages,
+        None => return,
+    };
+    for entry in stages {
+        let named = entry.as_str() == Some("ores") || entry["type"].as_str() == Some("ores");
+        if !named || !entry["ores"].is_null() {
+            continue;
+        }
+        if entry.is_string() {
+            *entry = serde_json::json!({ "type": "ores" });
+        }
+        entry["ores"] = rules(registry::get());
+    }
+}
+
+/// The ore rules of a block registry, as they're stored in an `ores` entry.
+fn rules(blocks: &BlockRegistry) -> serde_json::Value {
+    let name = |voxel_type| {
+        blocks
+            .get(voxel_type)
+            .map_or("air", |def| def.name.as_str())
+    };
+    blocks
+        .ores()
+        .map(|(voxel_type, ore)| {
+            serde_json::json!({
+                "block": name(voxel_type),
+                "height": [ore.height.0, ore.height.1],
+                "vein_size": ore.vein_size,
+                "frequency": ore.frequency,
+                "host": name(ore.host),
+            })
+        })
+        .collect()
+}
+
+impl GenerationStage for OreStage {
+    fn run(&self, context: &mut StageContext) {
+        let i = context.index;
+        let (bottom, top) = (i.chunk_origin().y, i.chunk_origin().y + CHUNK_DIM - 1);
+
+        for (voxel_type, ore) in &self.ores {
+            if ore.height.1 < bottom || ore.height.0 > top {
+                continue;
+            }
+
+            let starts = chunk::neighbour_offsets()
+                .map(|(x, y, z)| i.offset(x, y, z))
+                .chain(std::iter::once(i));
+            for start in starts {
+                for at in self.veins(*voxel_type, ore, start) {
+                    let index = VoxelIndex(at);
+                    if index.chunk_index() != i || at.y < ore.height.0 || at.y > ore.height.1 {
+                        continue;
+                    }
+                    let local = index.local_part();
+                    if context.chunk.voxel(local.x, local.y, local.z).voxel_type == ore.host {
+                        *context.chunk.voxel_mut(local.x, local.y, local.z) =
+                            Voxel::new(*voxel_type);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::gen::WorldGenerator;
+    use crate::world::stages::{StageRegistry, DEFAULT_STAGES};
+    use crate::world::TerrainPreset;
+
+    const ORE: VoxelType = VoxelType(5);
+
+    fn ore_rule() -> OreRule {
+        OreRule {
+            height: (-100, 100),
+            vein_size: 12,
+            frequency: 3.5,
+            host: VoxelType::GROUND,
+        }
+    }
+
+    fn generator() -> WorldGenerator {
+        let mut registry = StageRegistry::builtin();
+        registry.register("test_ores", |_, seed| {
+            Ok(Box::new(OreStage::new(seed, vec![(ORE, ore_rule())])))
+        });
+        let json = serde_json::json!({
+            "height": { "type": "constant", "value": 1000 },
+            "stages": ["terrain", "test_ores"],
+        });
+        let preset = TerrainPreset::with_stages(json, &registry).unwrap();
+        WorldGenerator::with_stages(21, preset, &registry).unwrap()
+    }
+
+    #[test]
+    pub fn veins_cross_chunk_borders() {
+        let generator = generator();
+        let mut chunks = std::collections::HashMap::new();
+        for (x, y, z) in chunk::neighbour_offsets().chain(std::iter::once((0, 0, 0))) {
+            let i: ChunkIndex = (x, y, z).into();
+            chunks.insert(i, generator.gen_chunk(i).0);
+        }
+
+        // Every voxel of the veins starting in the middle chunk is ore,
+        // whichever chunk it ended up in
+        let stage = OreStage::new(21, vec![(ORE, ore_rule())]);
+        let veins = stage.veins(ORE, &ore_rule(), (0, 0, 0).into());
+        assert!(!veins.is_empty());
+        let mut outside = 0;
+        for at in veins {
+            let index = VoxelIndex(at);
+            let local = index.local_part();
+            let chunk = &chunks[&index.chunk_index()];
+            assert_eq!(*chunk.voxel(local.x, local.y, local.z), Voxel::new(ORE));
+            outside += (index.chunk_index() != (0, 0, 0).into()) as usize;
+        }
+        assert!(outside > 0);
+
+        // The same chunk always gets the same ore
+        let again = generator.gen_chunk((0, 0, 0)).0;
+        assert_eq!(
+            again.voxels.to_vec(),
+            chunks[&(0, 0, 0).into()].voxels.to_vec()
+        );
+    }
+
+    #[test]
+    pub fn ores_stay_in_their_height_range() {
+        let mut registry = StageRegistry::builtin();
+        registry.register("test_ores", |_, seed| {
+            let ore = OreRule {
+                height: (-3, 2),
+                ..ore_rule()
+            };
+            Ok(Box::new(OreStage::new(seed, vec![(ORE, ore)])))
+        });
+        let json = serde_json::json!({
+            "height": { "type": "constant", "value": 1000 },
+            "stages": ["terrain", "test_ores"],
+        });
+        let preset = TerrainPreset::with_stages(json, &registry).unwrap();
+        let generator = WorldGenerator::with_stages(4, preset, &registry).unwrap();
+
+        let mut found = 0;
+        for y in -2..2 {
+            let i: ChunkIndex = (0, y, 0).into();
+            let (chunk, _) = generator.gen_chunk(i);
+            for x in 0..CHUNK_DIM {
+                for y in 0..CHUNK_DIM {
+                    for z in 0..CHUNK_DIM {
+                        if *chunk.voxel(x, y, z) == Voxel::new(ORE) {
+                            let world_y = i.chunk_origin().y + y;
+                            assert!(world_y >= -3 && world_y <= 2);
+                            found += 1;
+                        }
+                    }
+                }
+            }
+        }
+        assert!(found > 0);
+    }
+
+    #[test]
+    pub fn saved_presets_keep_their_ore_rules() {
+        // Presets from before there were ores don't get any
+        let generator = WorldGenerator::new(2, TerrainPreset::default());
+        let names: Vec<String> = generator
+            .stage_timings()
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        assert_eq!(names, DEFAULT_STAGES);
+
+        // The installed rules are written into the preset
+        let preset = TerrainPreset::from_value(serde_json::json!({
+            "height": { "type": "constant", "value": -100 },
+            "stages": ["terrain", "ores"],
+        }))
+        .unwrap();
+        assert_eq!(
+            preset.to_value()["stages"][1],
+            serde_json::json!({ "type": "ores", "ores": [] })
+        );
+
+        // and read back from it
+        let blocks = BlockRegistry::from_json(
+            r#"{ "blocks": [{ "id": 1, "name": "dirt", "ore": {
+                "height": [0, 3], "vein_size": 16, "frequency": 8, "host": "air"
+            } }] }"#,
+        )
+        .unwrap();
+        let json = serde_json::json!({
+            "height": { "type": "constant", "value": -100 },
+            "stages": [{ "type": "ores", "ores": rules(&blocks) }],
+        });
+        let preset = TerrainPreset::from_value(json).unwrap();
+        let stored = TerrainPreset::from_value(preset.to_value().clone()).unwrap();
+        assert_eq!(stored, preset);
+
+        let (chunk, _) = WorldGenerator::new(2, stored).gen_chunk((0, 0, 0));
+        let mut found = 0;
+        for x in 0..CHUNK_DIM {
+            for y in 0..CHUNK_DIM {
+                for z in 0..CHUNK_DIM {
+                    if *chunk.voxel(x, y, z) == Voxel::new(VoxelType::GROUND) {
+                        assert!(y <= 3);
+                        found += 1;
+                    }
+                }
+            }
+        }
+        assert!(found > 0);
+
+        // Blocks have to exist
+        let mut unknown = rules(&blocks);
+        unknown[0]["block"] = "coal".into();
+        let json = serde_json::json!({
+            "height": { "type": "constant", "value": -100 },
+            "stages": [{ "type": "ores", "ores": unknown }],
+        });
+        assert!(TerrainPreset::from_value(json).is_err());
+    }
+}
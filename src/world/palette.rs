@@ -0,0 +1,243 @@
+use crate::world::Voxel;
+
+use std::mem;
+
+/// Voxels of a chunk stored as indices into a small palette of the distinct
+/// voxels in it.
+///
+/// The indices are bit-packed into `u64` words with as few bits as the palette
+/// needs (1, 2, 4, 8 or 16), so no index straddles two words. A chunk made of
+/// a single voxel, like the open sky, has no indices at all.
+#[derive(Debug, Clone)]
+pub struct PalettedVoxels {
+    len: usize,
+    palette: Vec<Voxel>,
+    /// Bits per index, 0 while there's only one voxel in the palette.
+    bits: u32,
+    words: Vec<u64>,
+}
+
+fn bits_for(palette_len: usize) -> u32 {
+    match palette_len {
+        0..=1 => 0,
+        2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        17..=256 => 8,
+        _ => 16,
+    }
+}
+
+impl PalettedVoxels {
+    /// `len` copies of `voxel`.
+    pub fn uniform(len: usize, voxel: Voxel) -> PalettedVoxels {
+        PalettedVoxels {
+            len,
+            palette: vec![voxel],
+            bits: 0,
+            words: Vec::new(),
+        }
+    }
+
+    pub fn from_voxels(voxels: &[Voxel]) -> PalettedVoxels {
+        let mut palette: Vec<Voxel> = Vec::new();
+        let indices: Vec<usize> = voxels
+            .iter()
+            .map(|voxel| match palette.iter().position(|v| v == voxel) {
+                Some(n) => n,
+                None => {
+                    palette.push(*voxel);
+                    palette.len() - 1
+                }
+            })
+            .collect();
+        if palette.is_empty() {
+            palette.push(Voxel::void());
+        }
+
+        let mut paletted = PalettedVoxels {
+            len: voxels.len(),
+            palette,
+            bits: 0,
+            words: Vec::new(),
+        };
+        paletted.pack(&indices);
+        paletted
+    }
+
+    pub fn is_uniform(&self) -> bool {
+        self.bits == 0
+    }
+
+    /// Distinct voxels, some of which may no longer be used.
+    pub fn palette(&self) -> &[Voxel] {
+        &self.palette
+    }
+
+    pub fn get(&self, i: usize) -> &Voxel {
+        &self.palette[self.index(i)]
+    }
+
+    pub fn set(&mut self, i: usize, voxel: Voxel) {
+        let n = match self.palette.iter().position(|v| *v == voxel) {
+            Some(n) => n,
+            None => {
+                if bits_for(self.palette.len() + 1) > self.bits {
+                    // Edits leave voxels behind in the palette that aren't
+                    // used anymore, drop those before growing the indices.
+                    self.compact();
+                }
+                self.palette.push(voxel);
+                if bits_for(self.palette.len()) > self.bits {
+                    let indices = self.indices();
+                    self.pack(&indices);
+                }
+                self.palette.len() - 1
+            }
+        };
+
+        if self.bits > 0 {
+            let (word, shift) = self.position(i);
+            let mask = ((1u64 << self.bits) - 1) << shift;
+            self.words[word] = (self.words[word] & !mask) | ((n as u64) << shift);
+        }
+    }
+
+    /// All voxels in order.
+    pub fn to_vec(&self) -> Vec<Voxel> {
+        (0..self.len).map(|i| *self.get(i)).collect()
+    }
+
+    /// Drops unused voxels from the palette and shrinks the indices to fit.
+    pub fn compact(&mut self) {
+        let indices = self.indices();
+        let mut used = vec![false; self.palette.len()];
+        for n in indices.iter() {
+            used[*n] = true;
+        }
+        if used.iter().all(|u| *u) {
+            return;
+        }
+
+        let mut remap = vec![0; self.palette.len()];
+        let mut palette = Vec::new();
+        for (n, voxel) in self.palette.iter().enumerate() {
+            if used[n] {
+                remap[n] = palette.len();
+                palette.push(*voxel);
+            }
+        }
+        self.palette = palette;
+
+        let indices: Vec<usize> = indices.iter().map(|n| remap[*n]).collect();
+        self.pack(&indices);
+    }
+
+    /// Bytes allocated on the heap for the palette and the indices.
+    pub fn heap_usage(&self) -> usize {
+        self.palette.capacity() * mem::size_of::<Voxel>()
+            + self.words.capacity() * mem::size_of::<u64>()
+    }
+
+    fn per_word(&self) -> usize {
+        64 / self.bits as usize
+    }
+
+    fn position(&self, i: usize) -> (usize, u32) {
+        let per_word = self.per_word();
+        (i / per_word, (i % per_word) as u32 * self.bits)
+    }
+
+    fn index(&self, i: usize) -> usize {
+        if self.bits == 0 {
+            return 0;
+        }
+        let (word, shift) = self.position(i);
+        ((self.words[word] >> shift) & ((1u64 << self.bits) - 1)) as usize
+    }
+
+    fn indices(&self) -> Vec<usize> {
+        (0..self.len).map(|i| self.index(i)).collect()
+    }
+
+    // Stores `indices` with as many bits as the palette needs
+    fn pack(&mut self, indices: &[usize]) {
+        self.bits = bits_for(self.palette.len());
+        self.words = Vec::new();
+        if self.bits == 0 {
+            return;
+        }
+
+        let per_word = self.per_word();
+        self.words = vec![0; (self.len + per_word - 1) / per_word];
+        for (i, n) in indices.iter().enumerate() {
+            let (word, shift) = self.position(i);
+            self.words[word] |= (*n as u64) << shift;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::VoxelType;
+
+    #[test]
+    pub fn uniform_storage_has_no_indices() {
+        let voxels = PalettedVoxels::uniform(4096, Voxel::void());
+        assert!(voxels.is_uniform());
+        assert_eq!(*voxels.get(1234), Voxel::void());
+        assert_eq!(voxels.heap_usage(), mem::size_of::<Voxel>());
+    }
+
+    #[test]
+    pub fn indices_grow_with_the_palette() {
+        let mut voxels = PalettedVoxels::uniform(4096, Voxel::void());
+        voxels.set(7, Voxel::new(VoxelType(3)));
+        assert_eq!(voxels.bits, 1);
+        assert_eq!(voxels.words.len(), 4096 / 64);
+
+        for n in 0..20 {
+            voxels.set(100 + n, Voxel::new(VoxelType(10 + n as u16)));
+        }
+        assert_eq!(voxels.bits, 8);
+        assert_eq!(*voxels.get(7), Voxel::new(VoxelType(3)));
+        assert_eq!(*voxels.get(119), Voxel::new(VoxelType(29)));
+        assert_eq!(*voxels.get(120), Voxel::void());
+
+        let mut expected = vec![Voxel::void(); 4096];
+        expected[7] = Voxel::new(VoxelType(3));
+        for n in 0..20 {
+            expected[100 + n] = Voxel::new(VoxelType(10 + n as u16));
+        }
+        assert_eq!(voxels.to_vec(), expected);
+        assert_eq!(PalettedVoxels::from_voxels(&expected).to_vec(), expected);
+    }
+
+    #[test]
+    pub fn unused_voxels_are_dropped_from_the_palette() {
+        let mut voxels = PalettedVoxels::uniform(64, Voxel::void());
+        for id in 1..4 {
+            voxels.set(0, Voxel::new(VoxelType(id)));
+        }
+        assert_eq!(voxels.palette().len(), 4);
+
+        // Making room for a fifth voxel gets rid of the overwritten ones
+        voxels.set(1, Voxel::new(VoxelType(4)));
+        assert_eq!(
+            voxels.palette(),
+            &[
+                Voxel::void(),
+                Voxel::new(VoxelType(3)),
+                Voxel::new(VoxelType(4))
+            ]
+        );
+        assert_eq!(voxels.bits, 2);
+
+        voxels.set(0, Voxel::void());
+        voxels.set(1, Voxel::void());
+        voxels.compact();
+        assert!(voxels.is_uniform());
+        assert_eq!(voxels.to_vec(), vec![Voxel::void(); 64]);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::world::{VoxelIndex, World};
+
+use cgmath::prelude::*;
+use cgmath::{Point3, Vector3};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaycastHit {
+    pub voxel: VoxelIndex,
+    /// Normal of the face the ray entered through, zero if the ray started
+    /// inside the voxel.
+    pub normal: Vector3<i32>,
+    /// Distance from the origin to the point where the ray entered the voxel.
+    pub distance: f32,
+    /// The empty voxel the ray passed through right before the hit, which is
+    /// where a voxel placed against the hit face goes.
+    pub previous: Option<VoxelIndex>,
+}
+
+impl World {
+    /// Walks the voxels along a ray (Amanatides & Woo) and returns the first
+    /// solid one within `max_distance`.
+    ///
+    /// Returns `None` if nothing was hit, or if the ray reached a chunk which
+    /// isn't loaded before hitting anything.
+    pub fn raycast(
+        &self,
+        origin: Point3<f32>,
+        direction: Vector3<f32>,
+        max_distance: f32,
+    ) -> Option<RaycastHit> {
+        if direction.magnitude2() == 0.0 {
+            return None;
+        }
+        let direction = direction.normalize();
+
+        let mut voxel = Vector3::new(
+            origin.x.floor() as i32,
+            origin.y.floor() as i32,
+            origin.z.floor() as i32,
+        );
+
+        let mut step = Vector3::new(0, 0, 0);
+        // Distance along the ray until the next voxel border on each axis
+        let mut t_max = Vector3::new(std::f32::INFINITY, std::f32::INFINITY, std::f32::INFINITY);
+        // Distance along the ray between two voxel borders on each axis
+        let mut t_delta = t_max;
+
+        for axis in 0..3 {
+            let start = origin[axis];
+            if direction[axis] > 0.0 {
+                step[axis] = 1;
+                t_max[axis] = (start.floor() + 1.0 - start) / direction[axis];
+                t_delta[axis] = 1.0 / direction[axis];
+            } else if direction[axis] < 0.0 {
+                step[axis] = -1;
+                t_max[axis] = (start - start.floor()) / -direction[axis];
+                t_delta[axis] = 1.0 / -direction[axis];
+            }
+        }
+
+        let mut distance = 0.0;
+        let mut normal = Vector3::new(0, 0, 0);
+        let mut previous = None;
+
+        loop {
+            let index = VoxelIndex(voxel);
+            if self.try_voxel(index)?.is_solid() {
+                return Some(RaycastHit {
+                    voxel: index,
+                    normal,
+                    distance,
+                    previous,
+                });
+            }
+            previous = Some(index);
+
+            let axis = if t_max.x < t_max.y && t_max.x < t_max.z {
+                0
+            } else if t_max.y < t_max.z {
+                1
+            } else {
+                2
+            };
+
+            distance = t_max[axis];
+            if distance > max_distance {
+                return None;
+            }
+
+            voxel[axis] += step[axis];
+            t_max[axis] += t_delta[axis];
+            normal = Vector3::new(0, 0, 0);
+            normal[axis] = -step[axis];
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use crate::world::chunk::{Chunk, CHUNK_DIM, CHUNK_N_VOXELS, NEIGHBOURHOOD};
+use crate::world::{ChunkIndex, Voxel, VoxelType};
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Version of the region and level file formats. Saves with another version
+/// are rejected.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Edge length, in chunks, of the cube of chunks stored in one region file.
+pub const REGION_DIM: i32 = 8;
+const REGION_CHUNKS: usize = (REGION_DIM * REGION_DIM * REGION_DIM) as usize;
+
+const MAGIC: &[u8; 4] = b"VXRG";
+/// Magic, version and an offset and length for every chunk.
+const HEADER_LEN: usize = 8 + REGION_CHUNKS * 8;
+
+/// Chunks saved to disk, grouped into region files in a directory.
+///
+/// A region file starts with a header indexing where the chunks are in the
+/// file:
+///
+/// ```text
+/// "VXRG" | version: u32 | REGION_CHUNKS * (offset: u32, length: u32) | chunks
+/// ```
+///
+/// Chunks missing from the file have a length of 0. Each chunk is stored as
+/// the neighbours it got structures from, a bit per `neighbour_slot` in a
+/// `u32`, followed by runs of equal voxels, `(run length: u16, voxel type:
+/// u16)`, in the order of `Chunk::iter`. All numbers are little endian.
+///
+/// The store is shared with the chunk workers, which read chunks while the
+/// main thread writes them.
+pub struct RegionStore {
+    dir: PathBuf,
+    lock: Mutex<()>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+/// Region of a chunk and the chunk's slot in the region's header.
+fn region_of(i: ChunkIndex) -> ((i32, i32, i32), usize) {
+    let c = i.0;
+    let region = (
+        c.x.div_euclid(REGION_DIM),
+        c.y.div_euclid(REGION_DIM),
+        c.z.div_euclid(REGION_DIM),
+    );
+    let (x, y, z) = (
+        c.x.rem_euclid(REGION_DIM),
+        c.y.rem_euclid(REGION_DIM),
+        c.z.rem_euclid(REGION_DIM),
+    );
+    (
+        region,
+        (x + y * REGION_DIM + z * REGION_DIM * REGION_DIM) as usize,
+    )
+}
+
+pub fn encode_chunk(chunk: &Chunk) -> Vec<u8> {
+    let received = (0..NEIGHBOURHOOD)
+        .filter(|slot| chunk.received[*slot])
+        .fold(0u32, |bits, slot| bits | 1 << slot);
+    let mut bytes = received.to_le_bytes().to_vec();
+    let mut run: Option<(u16, VoxelType)> = None;
+    let mut push = |(length, voxel_type): (u16, VoxelType)| {
+        bytes.extend_from_slice(&length.to_le_bytes());
+        bytes.extend_from_slice(&voxel_type.0.to_le_bytes());
+    };
+
+    for z in 0..CHUNK_DIM {
+        for y in 0..CHUNK_DIM {
+            for x in 0..CHUNK_DIM {
+                let voxel_type = chunk.voxel(x, y, z).voxel_type;
+                run = match run {
+                    Some((length, t)) if t == voxel_type && length < u16::MAX => {
+                        Some((length + 1, t))
+                    }
+                    Some(finished) => {
+                        push(finished);
+                        Some((1, voxel_type))
+                    }
+                    None => Some((1, voxel_type)),
+                };
+            }
+        }
+    }
+    if let Some(finished) = run {
+        push(finished);
+    }
+
+    bytes
+}
+
+/// Decodes a chunk written by `encode_chunk`. It counts as modified, it
+/// wouldn't have been saved otherwise.
+pub fn decode_chunk(bytes: &[u8]) -> io::Result<Chunk> {
+    if bytes.len() < 4 || bytes.len() % 4 != 0 {
+        return Err(invalid(format!(
+            "Chunk data of {} bytes is cut off",
+            bytes.len()
+        )));
+    }
+    let received = read_u32(bytes, 0);
+
+    let mut voxels = Vec::with_capacity(CHUNK_N_VOXELS);
+    for run in bytes[4..].chunks(4) {
+        let length = read_u16(run, 0) as usize;
+        if voxels.len() + length > CHUNK_N_VOXELS {
+            return Err(invalid("Chunk data has too many voxels".to_string()));
+        }
+        let voxel = Voxel::new(VoxelType(read_u16(run, 2)));
+        voxels.extend(std::iter::repeat(voxel).take(length));
+    }
+    if voxels.len() != CHUNK_N_VOXELS {
+        return Err(invalid(format!(
+            "Chunk data has {} of {} voxels",
+            voxels.len(),
+            CHUNK_N_VOXELS
+        )));
+    }
+
+    let mut chunk = Chunk::void();
+    let mut voxels = voxels.into_iter();
+    chunk.iter_mut(|_, voxel| *voxel = voxels.next().unwrap());
+    for slot in 0..NEIGHBOURHOOD {
+        chunk.received[slot] = received & 1 << slot != 0;
+    }
+    chunk.modified = true;
+    Ok(chunk)
+}
+
+impl RegionStore {
+    /// Uses `dir` for the region files, creating it if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<RegionStore> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(RegionStore {
+            dir: dir.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn region_path(&self, (x, y, z): (i32, i32, i32)) -> PathBuf {
+        self.dir.join(format!("r.{}.{}.{}.region", x, y, z))
+    }
+
+    /// The stored chunk data of every slot of a region, all `None` if there's
+    /// no file for it yet.
+    fn read_region(&self, region: (i32, i32, i32)) -> io::Result<Vec<Option<Vec<u8>>>> {
+        let path = self.region_path(region);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![None; REGION_CHUNKS]),
+            Err(e) => return Err(e),
+        };
+
+        if bytes.len() < HEADER_LEN || &bytes[0..4] != MAGIC {
+            return Err(invalid(format!("{} is not a region file", path.display())));
+        }
+        let version = read_u32(&bytes, 4);
+        if version != FORMAT_VERSION {
+            return Err(invalid(format!(
+                "{} has version {}, expected {}",
+                path.display(),
+                version,
+                FORMAT_VERSION
+            )));
+        }
+
+        (0..REGION_CHUNKS)
+            .map(|slot| {
+                let offset = read_u32(&bytes, 8 + slot * 8) as usize;
+                let length = read_u32(&bytes, 12 + slot * 8) as usize;
+                if length == 0 {
+                    Ok(None)
+                } else if offset < HEADER_LEN || offset + length > bytes.len() {
+                    Err(invalid(format!("{} is cut off", path.display())))
+                } else {
+                    Ok(Some(bytes[offset..offset + length].to_vec()))
+                }
+            })
+            .collect()
+    }
+
+    fn write_region(&self, region: (i32, i32, i32), chunks: &[Option<Vec<u8>>]) -> io::Result<()> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+
+        let mut data = Vec::new();
+        for chunk in chunks {
+            let (offset, length) = match chunk {
+                Some(bytes) => (HEADER_LEN + data.len(), bytes.len()),
+                None => (0, 0),
+            };
+            header.extend_from_slice(&(offset as u32).to_le_bytes());
+            header.extend_from_slice(&(length as u32).to_le_bytes());
+            if let Some(bytes) = chunk {
+                data.extend_from_slice(bytes);
+            }
+        }
+        header.extend_from_slice(&data);
+
+        // A crash while writing leaves the old file intact
+        let path = self.region_path(region);
+        let temporary = path.with_extension("region.tmp");
+        fs::write(&temporary, &header)?;
+        fs::rename(&temporary, &path)
+    }
+
+    /// Reads a chunk, `None` if it was never saved.
+    pub fn read_chunk(&self, i: ChunkIndex) -> io::Result<Option<Chunk>> {
+        let (region, slot) = region_of(i);
+        let _guard = self.lock.lock().expect("Region lock poisoned.");
+
+        let mut stored = self.read_region(region)?;
+        match stored[slot].take() {
+            Some(bytes) => decode_chunk(&bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes a set of chunks, rewriting each touched region file once.
+    pub fn write_chunks<'a, I>(&self, chunks: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = (ChunkIndex, &'a Chunk)>,
+    {
+        let mut by_region: Vec<((i32, i32, i32), Vec<(usize, Vec<u8>)>)> = Vec::new();
+        for (i, chunk) in chunks {
+            let (region, slot) = region_of(i);
+            let encoded = (slot, encode_chunk(chunk));
+            match by_region.iter_mut().find(|(r, _)| *r == region) {
+                Some((_, slots)) => slots.push(encoded),
+                None => by_region.push((region, vec![encoded])),
+            }
+        }
+
+        let _guard = self.lock.lock().expect("Region lock poisoned.");
+        for (region, slots) in by_region {
+            let mut stored = self.read_region(region)?;
+            for (slot, bytes) in slots {
+                stored[slot] = Some(bytes);
+            }
+            self.write_region(region, &stored)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rs-tri-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    pub fn chunks_survive_encoding() {
+        let mut chunk = Chunk::gen_flat(10);
+        *chunk.voxel_mut(3, 12, 7) = Voxel::new(VoxelType(4));
+        chunk.received[5] = true;
+        let encoded = encode_chunk(&chunk);
+
+        // A run of ground and one of air per slice, the lamp splits one of them
+        assert_eq!(encoded.len(), 4 + (2 * CHUNK_DIM as usize + 2) * 4);
+        let decoded = decode_chunk(&encoded).unwrap();
+        assert!(decoded.modified);
+        assert_eq!(decoded.voxels.to_vec(), chunk.voxels.to_vec());
+        assert_eq!(decoded.received, chunk.received);
+
+        assert!(decode_chunk(&encoded[1..]).is_err());
+        assert!(decode_chunk(&encoded[4..]).is_err());
+    }
+
+    #[test]
+    pub fn region_files_store_chunks() {
+        let dir = temporary_dir("regions");
+        let store = RegionStore::open(&dir).unwrap();
+        let index = |i: (i32, i32, i32)| -> ChunkIndex { i.into() };
+
+        let flat = Chunk::gen_flat(3);
+        let mut lamp = Chunk::void();
+        *lamp.voxel_mut(1, 2, 3) = Voxel::new(VoxelType(4));
+        store
+            .write_chunks(vec![(index((0, 0, 0)), &flat), (index((-1, 2, 9)), &lamp)])
+            .unwrap();
+        // Overwrites the first chunk and keeps the other one in its region
+        store.write_chunks(vec![(index((0, 0, 0)), &lamp)]).unwrap();
+
+        let read = |i| store.read_chunk(index(i)).unwrap();
+        assert_eq!(
+            read((0, 0, 0)).unwrap().voxels.to_vec(),
+            lamp.voxels.to_vec()
+        );
+        assert_eq!(
+            read((-1, 2, 9)).unwrap().voxels.to_vec(),
+            lamp.voxels.to_vec()
+        );
+        assert!(read((1, 0, 0)).is_none());
+        assert!(read((100, 0, 0)).is_none());
+
+        // Other versions of the format are rejected
+        let path = store.region_path((0, 0, 0));
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+        assert!(store.read_chunk(index((0, 0, 0))).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
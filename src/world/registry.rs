@@ -0,0 +1,453 @@
+use crate::world::chunk::{CHUNK_DIM, CHUNK_N_VOXELS};
+use crate::world::VoxelType;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Face names in the order used for per-face data: -x, +x, -y, +y, -z, +z.
+pub const FACES: [&str; 6] = ["west", "east", "bottom", "top", "north", "south"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockDef {
+    pub id: VoxelType,
+    pub name: String,
+    /// Fills the whole voxel: it's meshed as a cube and raycasts hit it.
+    pub solid: bool,
+    /// Faces of neighbouring voxels stay visible through it.
+    pub transparent: bool,
+    /// Blocks the movement of the player.
+    pub collision: bool,
+    /// Block light level emitted, 0 for none.
+    pub emission: u8,
+    /// Texture file per face, ordered like `FACES`.
+    pub textures: [Option<String>; 6],
+    /// How the block is spread underground as ore, if it is.
+    pub ore: Option<OreRule>,
+}
+
+/// Where and how often veins of an ore are generated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OreRule {
+    /// Lowest and highest y of the ore, both inclusive.
+    pub height: (i32, i32),
+    /// Number of steps of the random walk making up a vein, at most
+    /// `CHUNK_DIM` so a vein only reaches into neighbouring chunks.
+    pub vein_size: i32,
+    /// Average number of veins starting in a chunk, at most one per voxel.
+    pub frequency: f64,
+    /// The only block veins replace.
+    pub host: VoxelType,
+}
+
+impl BlockDef {
+    fn air() -> BlockDef {
+        BlockDef {
+            id: VoxelType::VOID,
+            name: "air".to_string(),
+            solid: false,
+            transparent: true,
+            collision: false,
+            emission: 0,
+            textures: Default::default(),
+            ore: None,
+        }
+    }
+}
+
+/// All voxel types known to the game, indexed by their numeric id.
+#[derive(Debug, Clone)]
+pub struct BlockRegistry {
+    blocks: Vec<Option<BlockDef>>,
+    by_name: HashMap<String, VoxelType>,
+    /// Every distinct texture file, in the order of the texture array layers.
+    textures: Vec<String>,
+    /// Texture array layer of each face, indexed like `blocks`.
+    layers: Vec<[u32; 6]>,
+}
+
+impl BlockRegistry {
+    /// The registry used when none was installed: air and plain ground.
+    pub fn builtin() -> BlockRegistry {
+        let mut registry = BlockRegistry {
+            blocks: Vec::new(),
+            by_name: HashMap::new(),
+            textures: Vec::new(),
+            layers: Vec::new(),
+        };
+        registry.insert(BlockDef::air());
+        registry.insert(BlockDef {
+            id: VoxelType::GROUND,
+            name: "dirt".to_string(),
+            solid: true,
+            transparent: false,
+            collision: true,
+            emission: 0,
+            textures: [(); 6].map(|_| Some("dirt.jpg".to_string())),
+            ore: None,
+        });
+        registry
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<BlockRegistry, String> {
+        let mut file = File::open(path.as_ref())
+            .map_err(|e| format!("Failed to open {}: {}", path.as_ref().display(), e))?;
+        let mut buffer = String::new();
+        file.read_to_string(&mut buffer)
+            .map_err(|e| format!("Failed to read {}: {}", path.as_ref().display(), e))?;
+
+        BlockRegistry::from_json(&buffer)
+    }
+
+    /// Parses a block set like
+    ///
+    /// ```json
+    /// { "blocks": [{ "id": 3, "name": "stone", "textures": { "all": "stone.png" } }] }
+    /// ```
+    ///
+    /// `solid` defaults to true, `transparent` to false, `collision` to the
+    /// value of `solid` and `emission` to 0. Per-face textures are looked up
+    /// in `textures` by face name, then `side` for the horizontal faces, then
+    /// `all`. Id 0 is always air and can't be redefined.
+    ///
+    /// Ores have an `ore` rule with a `[min, max]` `height`, a `vein_size`, a
+    /// `frequency` and the name of the `host` block, see `OreRule`.
+    pub fn from_json(json: &str) -> Result<BlockRegistry, String> {
+        let data: serde_json::Value =
+            serde_json::from_str(json).map_err(|e| format!("Invalid block set: {}", e))?;
+        let blocks = data["blocks"]
+            .as_array()
+            .ok_or("Block set has no \"blocks\" array")?;
+
+        let mut registry = BlockRegistry {
+            blocks: Vec::new(),
+            by_name: HashMap::new(),
+            textures: Vec::new(),
+            layers: Vec::new(),
+        };
+        registry.insert(BlockDef::air());
+
+        // Hosts can be defined after their ores, they're looked up at the end
+        let mut hosts = Vec::new();
+        for block in blocks {
+            let name = block["name"]
+                .as_str()
+                .ok_or_else(|| format!("Block without a name: {}", block))?
+                .to_string();
+            let id = block["id"]
+                .as_u64()
+                .filter(|id| *id > 0 && *id <= u16::MAX as u64)
+                .ok_or_else(|| format!("Block {} needs an id between 1 and 65535", name))?;
+
+            let flag = |key: &str, default: bool| -> Result<bool, String> {
+                match &block[key] {
+                    serde_json::Value::Null => Ok(default),
+                    value => value
+                        .as_bool()
+                        .ok_or_else(|| format!("{}.{} is not a boolean", name, key)),
+                }
+            };
+            let solid = flag("solid", true)?;
+
+            let mut textures: [Option<String>; 6] = Default::default();
+            for (face, texture) in FACES.iter().zip(textures.iter_mut()) {
+                let horizontal = *face != "top" && *face != "bottom";
+                let t = &block["textures"];
+                *texture = t[*face]
+                    .as_str()
+                    .or_else(|| if horizontal { t["side"].as_str() } else { None })
+                    .or_else(|| t["all"].as_str())
+                    .map(|s| s.to_string());
+            }
+
+            let def = BlockDef {
+                id: VoxelType(id as u16),
+                solid,
+                transparent: flag("transparent", false)?,
+                collision: flag("collision", solid)?,
+                emission: block["emission"].as_u64().unwrap_or(0).min(15) as u8,
+                textures,
+                ore: match &block["ore"] {
+                    serde_json::Value::Null => None,
+                    ore => {
+                        let host = ore["host"]
+                            .as_str()
+                            .ok_or_else(|| format!("{}.ore needs a host block", name))?;
+                        hosts.push((id as usize, host.to_string()));
+                        Some(parse_ore(ore, &name)?)
+                    }
+                },
+                name,
+            };
+
+            if registry.get(def.id).is_some() {
+                return Err(format!("Block id {} is used twice", def.id.0));
+            }
+            if registry.by_name.contains_key(&def.name) {
+                return Err(format!("Block name {} is used twice", def.name));
+            }
+            registry.insert(def);
+        }
+
+        for (id, host) in hosts {
+            let host = registry
+                .find(&host)
+                .ok_or_else(|| format!("Unknown ore host {}", host))?;
+            if let Some(Some(BlockDef { ore: Some(ore), .. })) = registry.blocks.get_mut(id) {
+                ore.host = host;
+            }
+        }
+
+        Ok(registry)
+    }
+
+    fn insert(&mut self, def: BlockDef) {
+        let id = def.id.0 as usize;
+        if self.blocks.len() <= id {
+            self.blocks.resize(id + 1, None);
+            self.layers.resize(id + 1, [0; 6]);
+        }
+
+        for (face, texture) in def.textures.iter().enumerate() {
+            if let Some(texture) = texture {
+                let layer = match self.textures.iter().position(|t| t == texture) {
+                    Some(layer) => layer,
+                    None => {
+                        self.textures.push(texture.clone());
+                        self.textures.len() - 1
+                    }
+                };
+                self.layers[id][face] = layer as u32;
+            }
+        }
+
+        self.by_name.insert(def.name.clone(), def.id);
+        self.blocks[id] = Some(def);
+    }
+
+    pub fn get(&self, id: VoxelType) -> Option<&BlockDef> {
+        self.blocks.get(id.0 as usize).and_then(|def| def.as_ref())
+    }
+
+    pub fn find(&self, name: &str) -> Option<VoxelType> {
+        self.by_name.get(name).cloned()
+    }
+
+    /// Every ore with its rule, ordered by id.
+    pub fn ores(&self) -> impl Iterator<Item = (VoxelType, &OreRule)> {
+        self.blocks()
+            .filter_map(|def| def.ore.as_ref().map(|ore| (def.id, ore)))
+    }
+
+    /// All defined blocks, ordered by id.
+    pub fn blocks(&self) -> impl Iterator<Item = &BlockDef> {
+        self.blocks.iter().filter_map(|def| def.as_ref())
+    }
+
+    /// Texture files of all blocks without duplicates. The position of a file
+    /// is its layer in the texture array.
+    pub fn textures(&self) -> &[String] {
+        &self.textures
+    }
+
+    /// Texture array layer drawn on `face` (ordered like `FACES`) of a block.
+    /// Faces without a texture use layer 0.
+    pub fn texture_layer(&self, id: VoxelType, face: usize) -> u32 {
+        self.layers
+            .get(id.0 as usize)
+            .map_or(0, |layers| layers[face])
+    }
+
+    // Unknown ids behave like air, so a world saved with a bigger block set
+    // doesn't trap the player.
+    pub fn is_solid(&self, id: VoxelType) -> bool {
+        self.get(id).map_or(false, |def| def.solid)
+    }
+
+    pub fn is_transparent(&self, id: VoxelType) -> bool {
+        self.get(id).map_or(true, |def| def.transparent)
+    }
+
+    pub fn has_collision(&self, id: VoxelType) -> bool {
+        self.get(id).map_or(false, |def| def.collision)
+    }
+}
+
+// The host is filled in once every block is known
+pub(super) fn parse_ore(ore: &serde_json::Value, name: &str) -> Result<OreRule, String> {
+    let height = match ore["height"].as_array().map(|h| h.as_slice()) {
+        Some([low, high]) => {
+            let y = |y: &serde_json::Value| y.as_i64().and_then(|y| i32::try_from(y).ok());
+            match (y(low), y(high)) {
+                (Some(low), Some(high)) if low <= high => (low, high),
+                _ => return Err(format!("{}.ore has an invalid height", name)),
+            }
+        }
+        _ => return Err(format!("{}.ore needs a [min, max] height", name)),
+    };
+    let vein_size = ore["vein_size"]
+        .as_i64()
+        .filter(|size| *size >= 1 && *size <= CHUNK_DIM as i64)
+        .ok_or_else(|| format!("{}.ore needs a vein_size between 1 and {}", name, CHUNK_DIM))?;
+    let frequency = ore["frequency"]
+        .as_f64()
+        .filter(|frequency| *frequency >= 0.0 && *frequency <= CHUNK_N_VOXELS as f64)
+        .ok_or_else(|| {
+            format!(
+                "{}.ore needs a frequency between 0 and {}",
+                name, CHUNK_N_VOXELS
+            )
+        })?;
+
+    Ok(OreRule {
+        height,
+        vein_size: vein_size as i32,
+        frequency,
+        host: VoxelType::VOID,
+    })
+}
+
+static REGISTRY: OnceLock<BlockRegistry> = OnceLock::new();
+
+/// Makes `registry` the one returned by `get`. This has to happen before the
+/// first call to `get`, afterwards the registry is fixed and it's handed back.
+pub fn install(registry: BlockRegistry) -> Result<(), BlockRegistry> {
+    REGISTRY.set(registry)
+}
+
+/// The installed registry, or `BlockRegistry::builtin` if none was installed.
+pub fn get() -> &'static BlockRegistry {
+    REGISTRY.get_or_init(BlockRegistry::builtin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn parse_block_set() {
+        let registry = BlockRegistry::from_json(
+            r#"{ "blocks": [
+                { "id": 2, "name": "grass", "textures": { "top": "grass_top.png", "side": "grass_side.png", "all": "dirt.jpg" } },
+                { "id": 7, "name": "glass", "transparent": true, "textures": { "all": "glass.png" } },
+                { "id": 9, "name": "flower", "solid": false, "transparent": true, "emission": 20 }
+            ] }"#,
+        )
+        .unwrap();
+
+        let grass = registry.get(VoxelType(2)).unwrap();
+        assert_eq!(registry.find("grass"), Some(VoxelType(2)));
+        assert!(grass.solid && grass.collision && !grass.transparent);
+        assert_eq!(grass.textures[3].as_deref(), Some("grass_top.png"));
+        assert_eq!(grass.textures[2].as_deref(), Some("dirt.jpg"));
+        assert_eq!(grass.textures[0].as_deref(), Some("grass_side.png"));
+
+        assert!(registry.is_transparent(VoxelType(7)));
+        assert!(registry.has_collision(VoxelType(7)));
+
+        let flower = registry.get(VoxelType(9)).unwrap();
+        assert!(!flower.solid && !flower.collision);
+        assert_eq!(flower.emission, 15);
+
+        assert!(!registry.is_solid(VoxelType::VOID));
+        assert!(!registry.is_solid(VoxelType(5)));
+        assert_eq!(registry.blocks().count(), 4);
+    }
+
+    #[test]
+    pub fn ore_rules() {
+        let registry = BlockRegistry::from_json(
+            r#"{ "blocks": [
+                { "id": 4, "name": "coal", "ore": { "height": [-40, 20], "vein_size": 8, "frequency": 2.5, "host": "stone" } },
+                { "id": 3, "name": "stone" }
+            ] }"#,
+        )
+        .unwrap();
+
+        let ores: Vec<(VoxelType, &OreRule)> = registry.ores().collect();
+        assert_eq!(
+            ores,
+            vec![(
+                VoxelType(4),
+                &OreRule {
+                    height: (-40, 20),
+                    vein_size: 8,
+                    frequency: 2.5,
+                    host: VoxelType(3),
+                }
+            )]
+        );
+
+        let invalid = |ore: &str| {
+            let json = format!(
+                r#"{{ "blocks": [{{ "id": 1, "name": "stone" }}, {{ "id": 2, "name": "ore", "ore": {} }}] }}"#,
+                ore
+            );
+            BlockRegistry::from_json(&json).is_err()
+        };
+        assert!(invalid(
+            r#"{ "height": [0, 9], "vein_size": 4, "frequency": 1, "host": "gold" }"#
+        ));
+        assert!(invalid(
+            r#"{ "height": [9, 0], "vein_size": 4, "frequency": 1, "host": "stone" }"#
+        ));
+        assert!(invalid(
+            r#"{ "height": [0, 9], "vein_size": 40, "frequency": 1, "host": "stone" }"#
+        ));
+        assert!(invalid(
+            r#"{ "height": [0, 9], "vein_size": 4, "host": "stone" }"#
+        ));
+        assert!(invalid(
+            r#"{ "height": [0, 9], "vein_size": 4, "frequency": 1e12, "host": "stone" }"#
+        ));
+        assert!(invalid(
+            r#"{ "height": [-5000000000, 9], "vein_size": 4, "frequency": 1, "host": "stone" }"#
+        ));
+        assert!(!invalid(
+            r#"{ "height": [0, 9], "vein_size": 4, "frequency": 1, "host": "stone" }"#
+        ));
+    }
+
+    #[test]
+    pub fn texture_layers_are_shared() {
+        let registry = BlockRegistry::from_json(
+            r#"{ "blocks": [
+                { "id": 1, "name": "dirt", "textures": { "all": "dirt.jpg" } },
+                { "id": 2, "name": "grass", "textures": { "top": "grass_top.png", "side": "grass_side.png", "all": "dirt.jpg" } },
+                { "id": 3, "name": "barrier" }
+            ] }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            registry.textures(),
+            &["dirt.jpg", "grass_side.png", "grass_top.png"]
+        );
+
+        let grass: Vec<u32> = (0..6)
+            .map(|face| registry.texture_layer(VoxelType(2), face))
+            .collect();
+        assert_eq!(grass, vec![1, 1, 0, 2, 1, 1]);
+        assert_eq!(registry.texture_layer(VoxelType(1), 3), 0);
+        assert_eq!(registry.texture_layer(VoxelType(3), 3), 0);
+        assert_eq!(registry.texture_layer(VoxelType(9), 3), 0);
+    }
+
+    #[test]
+    pub fn reject_invalid_block_sets() {
+        assert!(BlockRegistry::from_json("{}").is_err());
+        assert!(BlockRegistry::from_json(r#"{ "blocks": [{ "id": 0, "name": "a" }] }"#).is_err());
+        assert!(BlockRegistry::from_json(r#"{ "blocks": [{ "id": 1 }] }"#).is_err());
+        assert!(BlockRegistry::from_json(
+            r#"{ "blocks": [{ "id": 1, "name": "a" }, { "id": 1, "name": "b" }] }"#
+        )
+        .is_err());
+        assert!(BlockRegistry::from_json(
+            r#"{ "blocks": [{ "id": 1, "name": "a", "solid": 1 }] }"#
+        )
+        .is_err());
+    }
+}
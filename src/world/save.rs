@@ -0,0 +1,246 @@
+use crate::world::region::{RegionStore, FORMAT_VERSION};
+use crate::world::{Chunk, ChunkIndex, TerrainPreset, World};
+
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+/// Level metadata stored next to the region files.
+const LEVEL_FILE: &str = "level.json";
+
+impl World {
+    /// Whether `dir` has a world written by `save`.
+    pub fn exists<P: AsRef<Path>>(dir: P) -> bool {
+        dir.as_ref().join(LEVEL_FILE).is_file()
+    }
+
+    /// Opens a world written by `save`. Its chunks aren't read right away,
+    /// streamed chunks are taken from the save if they're in it and generated
+    /// otherwise.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<World, String> {
+        let dir = dir.as_ref();
+        let path = dir.join(LEVEL_FILE);
+        let level = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let level: serde_json::Value = serde_json::from_str(&level)
+            .map_err(|e| format!("Invalid level file {}: {}", path.display(), e))?;
+
+        let version = level["version"]
+            .as_u64()
+            .ok_or_else(|| format!("{} has no version", path.display()))?;
+        if version != FORMAT_VERSION as u64 {
+            return Err(format!(
+                "{} has version {}, expected {}",
+                path.display(),
+                version,
+                FORMAT_VERSION
+            ));
+        }
+
+        let seed = level["seed"]
+            .as_u64()
+            .ok_or_else(|| format!("{} has no valid seed", path.display()))?;
+        if level["terrain"].is_null() {
+            return Err(format!("{} has no terrain", path.display()));
+        }
+        let preset = TerrainPreset::from_value(level["terrain"].clone())
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let store = RegionStore::open(dir)
+            .map_err(|e| format!("Failed to open {}: {}", dir.display(), e))?;
+        let mut world = World::new(seed, preset);
+        world.store = Some(Arc::new(store));
+        Ok(world)
+    }
+
+    /// Writes the level and every loaded chunk which was edited into `dir`.
+    /// From then on edited chunks are also saved there when they're unloaded.
+    ///
+    /// Chunks which were never edited aren't stored, they're generated again
+    /// when the world is loaded.
+    pub fn save<P: AsRef<Path>>(&mut self, dir: P) -> Result<(), String> {
+        let dir = dir.as_ref();
+        // Chunks unloaded earlier are written into the save they were
+        // unloaded from
+        self.flush_unloaded();
+        let moved = self.store.as_ref().map_or(true, |store| store.dir() != dir);
+        if moved {
+            let store = RegionStore::open(dir)
+                .map_err(|e| format!("Failed to open {}: {}", dir.display(), e))?;
+            // Chunks unloaded into the old save only exist there
+            if let Some(old) = &self.store {
+                copy_regions(old.dir(), dir)?;
+            }
+            self.store = Some(Arc::new(store));
+        }
+
+        let level = serde_json::json!({
+            "version": FORMAT_VERSION,
+            "seed": self.seed(),
+            "terrain": self.preset().to_value(),
+        });
+        let path = dir.join(LEVEL_FILE);
+        fs::write(&path, serde_json::to_string_pretty(&level).unwrap())
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+        // Unloaded chunks are only left over if writing them failed
+        let modified = self
+            .chunks
+            .iter()
+            .filter(|(_, (chunk, _))| chunk.modified)
+            .map(|(i, (chunk, _))| (*i, &**chunk))
+            .chain(self.unsaved.iter().map(|(i, (_, chunk))| (*i, &**chunk)));
+        self.store
+            .as_ref()
+            .unwrap()
+            .write_chunks(modified)
+            .map_err(|e| format!("Failed to save chunks into {}: {}", dir.display(), e))?;
+        self.unsaved.clear();
+        Ok(())
+    }
+
+    /// Keeps the edits of a chunk that's being unloaded, if the world has a
+    /// save to put them in. They're written by the next `send_unloaded`.
+    pub(super) fn save_unloaded(&mut self, i: ChunkIndex, chunk: Arc<Chunk>) {
+        if chunk.modified && self.store.is_some() {
+            self.unsaved.insert(i, (self.writer.sent, chunk));
+        }
+    }
+
+    /// Hands the chunks unloaded since the last call to the writer thread,
+    /// and forgets the ones it has written.
+    pub(super) fn send_unloaded(&mut self) {
+        while let Some(written) = self.writer.poll() {
+            self.forget_written(written);
+        }
+
+        let batch = self.writer.sent;
+        let chunks: Vec<(ChunkIndex, Arc<Chunk>)> = self
+            .unsaved
+            .iter()
+            .filter(|(_, (n, _))| *n == batch)
+            .map(|(i, (_, chunk))| (*i, chunk.clone()))
+            .collect();
+        if chunks.is_empty() {
+            return;
+        }
+        if let Some(store) = &self.store {
+            self.writer.send(store.clone(), chunks);
+        }
+    }
+
+    /// Waits until every unloaded chunk is written.
+    pub(super) fn flush_unloaded(&mut self) {
+        self.send_unloaded();
+        while let Some(written) = self.writer.wait() {
+            self.forget_written(written);
+        }
+    }
+
+    fn forget_written(&mut self, (batch, result): (u64, Result<(), String>)) {
+        match result {
+            Ok(()) => self.unsaved.retain(|_, (n, _)| *n != batch),
+            // They're kept and written by the next `save`
+            Err(e) => println!("Failed to save unloaded chunks: {}", e),
+        }
+    }
+}
+
+/// Writes unloaded chunks into the save on a thread of its own, so the
+/// region files aren't read and rewritten in the middle of a frame.
+///
+/// Batches are written one after the other in the order they're sent, an
+/// older copy of a chunk never overwrites a newer one. Until its batch is
+/// written, a chunk is kept in `World::unsaved`, where it's picked up if it's
+/// loaded again in the meantime.
+pub(super) struct ChunkWriter {
+    batches: Option<Sender<(Arc<RegionStore>, Vec<(ChunkIndex, Arc<Chunk>)>)>>,
+    written: Receiver<(u64, Result<(), String>)>,
+    thread: Option<thread::JoinHandle<()>>,
+    /// Number of batches sent, which is also the number of the next one.
+    pub sent: u64,
+    /// Number of batches written, or that failed to be.
+    done: u64,
+}
+
+impl ChunkWriter {
+    pub fn new() -> ChunkWriter {
+        let (batches, receiver) = channel::<(Arc<RegionStore>, Vec<(ChunkIndex, Arc<Chunk>)>)>();
+        let (sender, written) = channel();
+        let thread = thread::Builder::new()
+            .name("chunk writer".to_string())
+            .spawn(move || {
+                for (n, (store, chunks)) in receiver.into_iter().enumerate() {
+                    let result = store
+                        .write_chunks(chunks.iter().map(|(i, chunk)| (*i, &**chunk)))
+                        .map_err(|e| format!("{}: {}", store.dir().display(), e));
+                    if sender.send((n as u64, result)).is_err() {
+                        return;
+                    }
+                }
+            })
+            .expect("Failed to spawn chunk writer.");
+
+        ChunkWriter {
+            batches: Some(batches),
+            written,
+            thread: Some(thread),
+            sent: 0,
+            done: 0,
+        }
+    }
+
+    fn send(&mut self, store: Arc<RegionStore>, chunks: Vec<(ChunkIndex, Arc<Chunk>)>) {
+        self.batches
+            .as_ref()
+            .expect("Chunk writer is shut down.")
+            .send((store, chunks))
+            .expect("Chunk writer died.");
+        self.sent += 1;
+    }
+
+    /// The number and outcome of the next written batch, without blocking.
+    fn poll(&mut self) -> Option<(u64, Result<(), String>)> {
+        let written = self.written.try_recv().ok()?;
+        self.done += 1;
+        Some(written)
+    }
+
+    /// Like `poll`, but blocks until the next batch is written. `None` once
+    /// every batch sent is.
+    fn wait(&mut self) -> Option<(u64, Result<(), String>)> {
+        if self.done == self.sent {
+            return None;
+        }
+        let written = self.written.recv().expect("Chunk writer died.");
+        self.done += 1;
+        Some(written)
+    }
+}
+
+impl Drop for ChunkWriter {
+    fn drop(&mut self) {
+        // The writer finishes the batches it was sent, then returns
+        self.batches.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn copy_regions(from: &Path, to: &Path) -> Result<(), String> {
+    let entries =
+        fs::read_dir(from).map_err(|e| format!("Failed to read {}: {}", from.display(), e))?;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("Failed to read {}: {}", from.display(), e))?
+            .path();
+        if path.extension().map_or(false, |ext| ext == "region") {
+            fs::copy(&path, to.join(path.file_name().unwrap()))
+                .map_err(|e| format!("Failed to copy {}: {}", path.display(), e))?;
+        }
+    }
+    Ok(())
+}
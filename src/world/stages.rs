@@ -0,0 +1,482 @@
+use crate::world::biome::{block, Biome};
+use crate::world::caves::Caves;
+use crate::world::chunk::{Chunk, CHUNK_DIM};
+use crate::world::gen::{column_hash, roll, sub_seed};
+use crate::world::ores::OreStage;
+use crate::world::structures::StructureKind;
+use crate::world::superflat::LayersStage;
+use crate::world::{ChunkIndex, Voxel, VoxelIndex, VoxelType};
+
+use cgmath::Vector3;
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Stages run by presets which don't list their own `stages`. Worlds saved
+/// before presets could list them run these, so they can't change without
+/// changing those worlds.
+pub const DEFAULT_STAGES: [&str; 4] = ["terrain", "surface", "carving", "decoration"];
+
+/// Depth of the subsurface layer below the surface voxel.
+const SUBSURFACE_DEPTH: usize = 3;
+
+/// The biome and the blended surface height of a column.
+#[derive(Clone, Copy)]
+pub struct Column<'a> {
+    pub biome: &'a Biome,
+    pub height: f64,
+}
+
+/// Everything a stage gets to see of the chunk being generated.
+pub struct StageContext<'a> {
+    pub seed: u64,
+    pub index: ChunkIndex,
+    pub caves: &'a Caves,
+    /// Columns of the chunk, see `column`.
+    pub columns: &'a [Column<'a>],
+    /// The chunk as the earlier stages left it.
+    pub chunk: &'a mut Chunk,
+    /// Voxels reaching out into the neighbouring chunks, by world position.
+    /// They're only placed where a neighbour has empty space.
+    pub spilled: &'a mut Vec<(VoxelIndex, Voxel)>,
+    /// The stages which already ran, see `is_ground`.
+    pub(super) earlier: &'a [&'a dyn GenerationStage],
+}
+
+impl<'a> StageContext<'a> {
+    /// The column at a position within the chunk.
+    pub fn column(&self, x: i32, z: i32) -> &Column<'a> {
+        &self.columns[(x * CHUNK_DIM + z) as usize]
+    }
+
+    /// Whether there's ground at a position relative to the chunk's origin.
+    /// x and z have to be within the chunk, but y can be above or below it.
+    /// Inside the chunk every voxel that isn't void is ground, outside of it
+    /// the stages which already ran describe what they left there.
+    pub fn is_ground(&self, x: i32, y: i32, z: i32) -> bool {
+        if y >= 0 && y < CHUNK_DIM {
+            return *self.chunk.voxel(x, y, z) != Voxel::void();
+        }
+
+        let at = self.index.chunk_origin() + Vector3::new(x, y, z);
+        let column = self.column(x, z);
+        self.earlier.iter().fold(false, |ground, stage| {
+            stage.is_ground(self.caves, column, at, ground)
+        })
+    }
+}
+
+/// One step of generating a chunk. The stages of a preset run in order on
+/// every chunk, each one picking up where the previous one left off.
+///
+/// Stages run on the chunk workers, so they have to be shareable between
+/// threads. Like the rest of the generator they may only depend on the seed
+/// and the position, or chunks would differ depending on when they're made.
+pub trait GenerationStage: Send + Sync {
+    fn run(&self, context: &mut StageContext);
+
+    /// Whether a voxel at a world position is ground after the stage ran on
+    /// its chunk, given whether it was before. Stages only get to change
+    /// their own chunk, this is how the later ones see past it, like the
+    /// surface stage looking at the ground above the chunk. Stages which add
+    /// or remove ground have to describe it here, the default leaves the
+    /// voxel as it was.
+    fn is_ground(&self, _caves: &Caves, _column: &Column, _at: Vector3<i32>, ground: bool) -> bool {
+        ground
+    }
+}
+
+/// Builds a stage from its entry in the preset's `stages` and the world seed.
+pub type StageFactory = fn(&serde_json::Value, u64) -> Result<Box<dyn GenerationStage>, String>;
+
+/// A stage of a generator with the time spent in it so far.
+pub struct StageTiming {
+    pub name: String,
+    pub total: Duration,
+    /// Number of chunks the stage ran on.
+    pub runs: u64,
+}
+
+/// Every stage a preset can name in its `stages`.
+///
+/// A stage is named either by a string or by an object with a `type` and
+/// whatever other settings its factory reads:
+///
+/// ```json
+/// { "stages": ["terrain", "surface", { "type": "flatten", "height": 12 }] }
+/// ```
+#[derive(Debug, Clone)]
+pub struct StageRegistry {
+    factories: HashMap<String, StageFactory>,
+}
+
+impl StageRegistry {
+    /// The registry used when none was installed, with the stages in
+    /// `DEFAULT_STAGES`, the `ores` and the `layers` of superflat worlds.
+    pub fn builtin() -> StageRegistry {
+        let mut registry = StageRegistry {
+            factories: HashMap::new(),
+        };
+        registry.register("terrain", |_, _| {
+            Ok(Box::new(TerrainStage {
+                stone: block("stone"),
+            }))
+        });
+        registry.register("surface", |_, _| Ok(Box::new(SurfaceStage)));
+        registry.register("ores", OreStage::build);
+        registry.register("carving", |_, _| Ok(Box::new(CarvingStage)));
+        registry.register("decoration", |_, _| Ok(Box::new(DecorationStage)));
+        registry.register("layers", LayersStage::build);
+        registry
+    }
+
+    /// Adds a stage, replacing any stage with the same name.
+    pub fn register(&mut self, name: &str, factory: StageFactory) {
+        self.factories.insert(name.to_string(), factory);
+    }
+
+    /// Builds the stages of a preset in order, with their names.
+    pub fn build(
+        &self,
+        preset: &serde_json::Value,
+        seed: u64,
+    ) -> Result<Vec<(String, Box<dyn GenerationStage>)>, String> {
+        let default: Vec<serde_json::Value>;
+        let entries = match &preset["stages"] {
+            serde_json::Value::Null => {
+                default = DEFAULT_STAGES.iter().map(|name| (*name).into()).collect();
+                &default
+            }
+            stages => stages
+                .as_array()
+                .ok_or("stages needs to be a list of stages")?,
+        };
+
+        entries
+            .iter()
+            .map(|entry| {
+                let name = entry
+                    .as_str()
+                    .or_else(|| entry["type"].as_str())
+                    .ok_or("A stage needs to be a name or have a type")?;
+                let factory = self
+                    .factories
+                    .get(name)
+                    .ok_or_else(|| format!("Unknown generation stage {}", name))?;
+                let stage = factory(entry, seed).map_err(|e| format!("Stage {}: {}", name, e))?;
+                Ok((name.to_string(), stage))
+            })
+            .collect()
+    }
+}
+
+static STAGES: OnceLock<StageRegistry> = OnceLock::new();
+
+/// Makes `registry` the one returned by `get`. Like the block registry this
+/// has to happen before the first preset is loaded.
+pub fn install(registry: StageRegistry) -> Result<(), StageRegistry> {
+    STAGES.set(registry)
+}
+
+/// The installed registry, or `StageRegistry::builtin` if none was installed.
+pub fn get() -> &'static StageRegistry {
+    STAGES.get_or_init(StageRegistry::builtin)
+}
+
+/// Fills the ground of the density field with stone.
+struct TerrainStage {
+    stone: VoxelType,
+}
+
+impl GenerationStage for TerrainStage {
+    fn run(&self, context: &mut StageContext) {
+        let origin = context.index.chunk_origin();
+        let (caves, columns) = (context.caves, context.columns);
+        let stone = Voxel::new(self.stone);
+
+        context.chunk.iter_mut(|(x, y, z), v| {
+            let height = columns[(x * CHUNK_DIM + z) as usize].height;
+            *v = if caves.is_ground(origin.x + x, origin.y + y, origin.z + z, height) {
+                stone
+            } else {
+                Voxel::void()
+            };
+        });
+    }
+
+    fn is_ground(&self, caves: &Caves, column: &Column, at: Vector3<i32>, _: bool) -> bool {
+        caves.is_ground(at.x, at.y, at.z, column.height)
+    }
+}
+
+/// Covers the top of the ground with the surface and subsurface voxels of the
+/// biome.
+struct SurfaceStage;
+
+impl GenerationStage for SurfaceStage {
+    fn run(&self, context: &mut StageContext) {
+        for x in 0..CHUNK_DIM {
+            for z in 0..CHUNK_DIM {
+                let biome = context.column(x, z).biome;
+
+                // The ground above the chunk decides how deep the voxels at
+                // its top are
+                let mut above = (0..=SUBSURFACE_DEPTH as i32)
+                    .take_while(|y| context.is_ground(x, CHUNK_DIM + y, z))
+                    .count();
+
+                for y in (0..CHUNK_DIM).rev() {
+                    if *context.chunk.voxel(x, y, z) == Voxel::void() {
+                        above = 0;
+                        continue;
+                    }
+                    if above == 0 {
+                        *context.chunk.voxel_mut(x, y, z) = Voxel::new(biome.surface);
+                    } else if above <= SUBSURFACE_DEPTH {
+                        *context.chunk.voxel_mut(x, y, z) = Voxel::new(biome.subsurface);
+                    }
+                    above = (above + 1).min(SUBSURFACE_DEPTH + 1);
+                }
+            }
+        }
+    }
+}
+
+/// Carves the caves out of the ground.
+struct CarvingStage;
+
+impl GenerationStage for CarvingStage {
+    fn run(&self, context: &mut StageContext) {
+        let origin = context.index.chunk_origin();
+        let caves = context.caves;
+
+        context.chunk.iter_mut(|(x, y, z), v| {
+            if *v != Voxel::void() && caves.is_carved(origin.x + x, origin.y + y, origin.z + z) {
+                *v = Voxel::void();
+            }
+        });
+    }
+
+    fn is_ground(&self, caves: &Caves, _: &Column, at: Vector3<i32>, ground: bool) -> bool {
+        ground && !caves.is_carved(at.x, at.y, at.z)
+    }
+}
+
+/// Places the decorations and structures of the biomes.
+struct DecorationStage;
+
+impl DecorationStage {
+    /// Voxel type and stack height of the decoration on a column, if it has
+    /// one. It starts right above the surface.
+    fn decoration(seed: u64, biome: &Biome, x: i32, z: i32) -> Option<(VoxelType, i32)> {
+        let mut hash = column_hash(sub_seed(seed, "decorations"), x, z);
+        for decoration in &biome.decorations {
+            let roll = roll(hash);
+            hash = column_hash(hash as u32, x, z);
+            if roll < decoration.chance {
+                let (low, high) = decoration.height;
+                let height = low + (hash % (high - low + 1) as u64) as i32;
+                return Some((decoration.voxel_type, height));
+            }
+        }
+        None
+    }
+
+    /// Kind of the structure on a column, if it has one, and the hash
+    /// picking its variation.
+    fn structure(seed: u64, biome: &Biome, x: i32, z: i32) -> Option<(StructureKind, u64)> {
+        let mut hash = column_hash(sub_seed(seed, "structures"), x, z);
+        for rule in &biome.structures {
+            let roll = roll(hash);
+            hash = column_hash(hash as u32, x, z);
+            if roll < rule.chance {
+                return Some((rule.kind, hash));
+            }
+        }
+        None
+    }
+}
+
+impl GenerationStage for DecorationStage {
+    fn run(&self, context: &mut StageContext) {
+        let i = context.index;
+        let origin = i.chunk_origin();
+
+        // Decorations and structures stand on every surface voxel of their
+        // column, which can be more than one with overhangs, but not on the
+        // floor of a cave. The surfaces are all found before anything is
+        // placed, so nothing ends up standing on leaves
+        let mut columns = Vec::with_capacity((CHUNK_DIM * CHUNK_DIM) as usize);
+        for x in 0..CHUNK_DIM {
+            for z in 0..CHUNK_DIM {
+                let (wx, wz) = (origin.x + x, origin.z + z);
+                let biome = context.column(x, z).biome;
+                let decoration = DecorationStage::decoration(context.seed, biome, wx, wz);
+
+                // Decorations standing below the chunk can reach into it
+                let stack = decoration.map_or(0, |(_, stack)| stack);
+                let surfaces: Vec<i32> = (-stack..CHUNK_DIM)
+                    .filter(|y| {
+                        context.is_ground(x, *y, z)
+                            && !context.is_ground(x, y + 1, z)
+                            && !context.caves.is_carved(wx, origin.y + y + 1, wz)
+                    })
+                    .collect();
+                columns.push((x, z, biome, decoration, surfaces));
+            }
+        }
+
+        for (x, z, biome, decoration, surfaces) in columns {
+            let (wx, wz) = (origin.x + x, origin.z + z);
+            if let Some((voxel_type, stack)) = decoration {
+                for surface in surfaces {
+                    for y in (surface + 1).max(0)..(surface + 1 + stack).min(CHUNK_DIM) {
+                        if *context.chunk.voxel(x, y, z) == Voxel::void() {
+                            *context.chunk.voxel_mut(x, y, z) = Voxel::new(voxel_type);
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let (kind, hash) = match DecorationStage::structure(context.seed, biome, wx, wz) {
+                Some(structure) => structure,
+                None => continue,
+            };
+            for y in surfaces {
+                let at = Vector3::new(wx, origin.y + y + 1, wz);
+                for (offset, voxel_type) in kind.build(hash) {
+                    let index = VoxelIndex(at + offset);
+                    if index.chunk_index() != i {
+                        context.spilled.push((index, Voxel::new(voxel_type)));
+                        continue;
+                    }
+                    let local = index.local_part();
+                    if *context.chunk.voxel(local.x, local.y, local.z) == Voxel::void() {
+                        *context.chunk.voxel_mut(local.x, local.y, local.z) =
+                            Voxel::new(voxel_type);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::gen::WorldGenerator;
+    use crate::world::TerrainPreset;
+
+    /// Levels the ground at a fixed height, replacing the terrain stage.
+    struct FlattenStage {
+        height: i32,
+    }
+
+    impl GenerationStage for FlattenStage {
+        fn run(&self, context: &mut StageContext) {
+            let origin = context.index.chunk_origin();
+            let height = self.height;
+            context.chunk.iter_mut(|(_, y, _), v| {
+                *v = if origin.y + y < height {
+                    Voxel::new(VoxelType::GROUND)
+                } else {
+                    Voxel::void()
+                };
+            });
+        }
+
+        fn is_ground(&self, _: &Caves, _: &Column, at: Vector3<i32>, _: bool) -> bool {
+            at.y < self.height
+        }
+    }
+
+    /// A generator flattening the ground at 16, the top of the first chunk,
+    /// instead of at the height of the biome.
+    fn flattened(biome: serde_json::Value, stages: &[&str]) -> WorldGenerator {
+        let mut registry = StageRegistry::builtin();
+        registry.register("flatten", |_, _| Ok(Box::new(FlattenStage { height: 16 })));
+
+        let mut biome = biome;
+        biome["name"] = "flat".into();
+        biome["temperature"] = 0.into();
+        biome["humidity"] = 0.into();
+        biome["height"] = serde_json::json!({ "type": "constant", "value": 40 });
+        let json = serde_json::json!({ "biomes": [biome], "stages": stages });
+        let preset = TerrainPreset::with_stages(json, &registry).unwrap();
+        WorldGenerator::with_stages(8, preset, &registry).unwrap()
+    }
+
+    #[test]
+    pub fn stages_see_the_ground_of_earlier_stages() {
+        // Nothing is above the top of the first chunk, so that's the surface
+        let generator = flattened(
+            serde_json::json!({ "surface": "air", "subsurface": "dirt" }),
+            &["flatten", "surface"],
+        );
+        let (chunk, _) = generator.gen_chunk((1, 0, 2));
+        assert_eq!(*chunk.voxel(6, 15, 6), Voxel::void());
+        assert_eq!(*chunk.voxel(6, 14, 6), Voxel::new(VoxelType::GROUND));
+
+        // Decorations stand on the flattened ground, not on the height map
+        let generator = flattened(
+            serde_json::json!({
+                "decorations": [{ "block": "dirt", "chance": 1, "height": [2, 2] }]
+            }),
+            &["flatten", "surface", "decoration"],
+        );
+        let (above, _) = generator.gen_chunk((1, 1, 2));
+        let (height_map, _) = generator.gen_chunk((1, 2, 2));
+        for (x, z) in &[(0, 0), (6, 6), (15, 9)] {
+            assert_eq!(*above.voxel(*x, 0, *z), Voxel::new(VoxelType::GROUND));
+            assert_eq!(*above.voxel(*x, 1, *z), Voxel::new(VoxelType::GROUND));
+            assert_eq!(*above.voxel(*x, 2, *z), Voxel::void());
+            assert_eq!(*height_map.voxel(*x, 8, *z), Voxel::void());
+        }
+    }
+
+    #[test]
+    pub fn presets_pick_their_stages() {
+        let mut registry = StageRegistry::builtin();
+        registry.register("flatten", |entry, _| {
+            let height = entry["height"].as_i64().ok_or("needs a height")?;
+            Ok(Box::new(FlattenStage {
+                height: height as i32,
+            }))
+        });
+
+        let json: serde_json::Value = serde_json::from_str(
+            r#"{
+                "height": { "type": "constant", "value": 40 },
+                "stages": [{ "type": "flatten", "height": 5 }, "surface"]
+            }"#,
+        )
+        .unwrap();
+        let preset = TerrainPreset::with_stages(json, &registry).unwrap();
+        let generator = WorldGenerator::with_stages(3, preset, &registry).unwrap();
+
+        // Flat at 5 instead of the preset's height of 40
+        let (chunk, _) = generator.gen_chunk((0, 0, 0));
+        assert_eq!(*chunk.voxel(3, 4, 3), Voxel::new(block("grass")));
+        assert_eq!(*chunk.voxel(3, 5, 3), Voxel::void());
+
+        let timings = generator.stage_timings();
+        let names: Vec<&str> = timings.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["flatten", "surface"]);
+        assert!(timings.iter().all(|t| t.runs == 1));
+
+        // Stages have to be known to the registry, and get their settings
+        let invalid = |stages: &str| {
+            let json = format!(
+                r#"{{ "height": {{ "type": "constant", "value": 1 }}, "stages": {} }}"#,
+                stages
+            );
+            TerrainPreset::with_stages(serde_json::from_str(&json).unwrap(), &registry).is_err()
+        };
+        assert!(invalid(r#"["flatten"]"#));
+        assert!(invalid(r#"["terrain", "erosion"]"#));
+        assert!(invalid(r#""terrain""#));
+        assert!(!invalid(r#"["terrain", "decoration"]"#));
+    }
+}
@@ -0,0 +1,79 @@
+use crate::world::{ChunkIndex, World};
+
+/// Size, in chunks, of the area kept loaded around the player: a cylinder
+/// with a horizontal radius and a height above and below the player's chunk.
+///
+/// The unload distances should be larger than the load distances, so walking
+/// back and forth over a chunk border doesn't load and unload the same chunks
+/// over and over again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamingConfig {
+    pub load_radius: i32,
+    pub unload_radius: i32,
+    pub load_height: i32,
+    pub unload_height: i32,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> StreamingConfig {
+        StreamingConfig {
+            load_radius: 6,
+            unload_radius: 8,
+            load_height: 3,
+            unload_height: 5,
+        }
+    }
+}
+
+fn distance_squared(a: ChunkIndex, b: ChunkIndex) -> i32 {
+    let d = a.0 - b.0;
+    d.x * d.x + d.y * d.y + d.z * d.z
+}
+
+fn within(i: ChunkIndex, center: ChunkIndex, radius: i32, height: i32) -> bool {
+    let d = i.0 - center.0;
+    d.x * d.x + d.z * d.z <= radius * radius && d.y.abs() <= height
+}
+
+impl World {
+    /// Requests every chunk within the load distances of `center`, nearest
+    /// first, and drops loaded or pending chunks beyond the unload distances.
+    pub fn stream_around(&mut self, center: ChunkIndex, config: &StreamingConfig) {
+        if self.streamed_around == Some((center, *config)) {
+            return;
+        }
+        self.streamed_around = Some((center, *config));
+
+        let outside =
+            |i: &ChunkIndex| !within(*i, center, config.unload_radius, config.unload_height);
+
+        let far_away: Vec<ChunkIndex> = self.chunks.keys().cloned().filter(outside).collect();
+        for i in far_away {
+            self.unload_chunk(i);
+        }
+
+        let far_pending: Vec<ChunkIndex> =
+            self.generating.iter().cloned().filter(outside).collect();
+        for i in far_pending {
+            self.cancel_chunk(i);
+        }
+
+        let (r, h) = (config.load_radius, config.load_height);
+        let mut wanted = Vec::new();
+        for x in -r..=r {
+            for y in -h..=h {
+                for z in -r..=r {
+                    let i = center.offset(x, y, z);
+                    if within(i, center, r, h) {
+                        wanted.push(i);
+                    }
+                }
+            }
+        }
+        wanted.sort_by_key(|i| distance_squared(*i, center));
+
+        for i in wanted {
+            self.request_chunk(i);
+        }
+    }
+}
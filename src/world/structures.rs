@@ -0,0 +1,128 @@
+use cgmath::Vector3;
+
+use crate::world::biome::block;
+use crate::world::VoxelType;
+
+/// Multi-voxel structures standing on the surface. Unlike decorations they
+/// can be wider than a column and reach into the neighbouring chunks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StructureKind {
+    Tree,
+    Boulder,
+    /// The broken walls of a small stone hut.
+    Ruin,
+}
+
+/// How often a kind of structure shows up in a biome.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructureRule {
+    pub kind: StructureKind,
+    /// Share of the columns that get one, between 0 and 1.
+    pub chance: f64,
+}
+
+/// `width` bits of a hash, different ones for every `n`.
+fn bits(hash: u64, n: u32, width: u32) -> u64 {
+    (hash >> (n * width % 64)) & ((1 << width) - 1)
+}
+
+impl StructureKind {
+    pub fn parse(name: &str) -> Option<StructureKind> {
+        match name {
+            "tree" => Some(StructureKind::Tree),
+            "boulder" => Some(StructureKind::Boulder),
+            "ruin" => Some(StructureKind::Ruin),
+            _ => None,
+        }
+    }
+
+    /// The voxels of a structure relative to its origin, the voxel right
+    /// above the surface it stands on. `hash` picks the variation, so a
+    /// column always gets the same structure.
+    ///
+    /// Structures stay within `CHUNK_DIM` of their origin, so they only ever
+    /// spill into the chunks right next to the one they stand in.
+    pub fn build(self, hash: u64) -> Vec<(Vector3<i32>, VoxelType)> {
+        let mut voxels = Vec::new();
+        match self {
+            StructureKind::Tree => {
+                let (log, leaves) = (block("log"), block("leaves"));
+                let trunk = 4 + (hash % 3) as i32;
+                for y in trunk - 2..=trunk + 1 {
+                    let radius: i32 = if y < trunk { 2 } else { 1 };
+                    for x in -radius..=radius {
+                        for z in -radius..=radius {
+                            // Leave out some corners, and the trunk
+                            let corner = x.abs() == radius && z.abs() == radius;
+                            let cell = (x + z * 5 + y * 25) as u32;
+                            let trimmed = corner && (y == trunk + 1 || bits(hash, cell, 1) == 0);
+                            if trimmed || (x == 0 && z == 0 && y < trunk) {
+                                continue;
+                            }
+                            voxels.push((Vector3::new(x, y, z), leaves));
+                        }
+                    }
+                }
+                voxels.extend((0..trunk).map(|y| (Vector3::new(0, y, 0), log)));
+            }
+            StructureKind::Boulder => {
+                let stone = block("stone");
+                let radius = 1 + (hash % 2) as i32;
+                // Half sunk into the ground, only the open voxels are filled
+                for x in -radius..=radius {
+                    for y in -radius..=radius {
+                        for z in -radius..=radius {
+                            if x * x + y * y + z * z <= radius * radius + 1 {
+                                voxels.push((Vector3::new(x, y + radius - 1, z), stone));
+                            }
+                        }
+                    }
+                }
+            }
+            StructureKind::Ruin => {
+                let stone = block("stone");
+                for x in -2i32..=2 {
+                    for z in -2i32..=2 {
+                        if x.abs() != 2 && z.abs() != 2 {
+                            continue;
+                        }
+                        let cell = ((x + 2) + (z + 2) * 5) as u32;
+                        let height = bits(hash, cell, 2) as i32;
+                        voxels.extend((0..height).map(|y| (Vector3::new(x, y, z), stone)));
+                    }
+                }
+            }
+        }
+        voxels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::chunk::CHUNK_DIM;
+
+    #[test]
+    pub fn structures_stay_near_their_origin() {
+        for kind in &[
+            StructureKind::Tree,
+            StructureKind::Boulder,
+            StructureKind::Ruin,
+        ] {
+            for hash in 0..64u64 {
+                let voxels = kind.build(hash.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+                assert_eq!(voxels, kind.build(hash.wrapping_mul(0x9e37_79b9_7f4a_7c15)));
+                for (offset, _) in voxels {
+                    assert!(offset.x.abs() < CHUNK_DIM && offset.z.abs() < CHUNK_DIM);
+                    assert!(offset.y > -CHUNK_DIM && offset.y < CHUNK_DIM);
+                }
+            }
+        }
+
+        // The trunk goes up from the origin
+        let tree = StructureKind::Tree.build(1);
+        assert!(tree.contains(&(Vector3::new(0, 0, 0), block("log"))));
+        assert_eq!(StructureKind::parse("ruin"), Some(StructureKind::Ruin));
+        assert_eq!(StructureKind::parse("castle"), None);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::world::caves::Caves;
+use crate::world::chunk::Chunk;
+use crate::world::registry;
+use crate::world::stages::{Column, GenerationStage, StageContext};
+use crate::world::{TerrainPreset, VoxelType};
+
+use cgmath::Vector3;
+
+/// Reads a layer list like `"1 bedrock, 3 stone, 1 grass"`, bottom layer
+/// first. A layer without a count is one voxel thick, `air` leaves a gap.
+pub fn parse_layers(layers: &str) -> Result<Vec<(VoxelType, i32)>, String> {
+    let parsed = layers
+        .split(',')
+        .map(|layer| {
+            let words: Vec<&str> = layer.split_whitespace().collect();
+            let (count, name) = match words.as_slice() {
+                [name] => (1, *name),
+                [count, name] => match count.parse::<i32>() {
+                    Ok(count) if count > 0 => (count, *name),
+                    _ => return Err(format!("Invalid layer count in \"{}\"", layer.trim())),
+                },
+                _ => return Err(format!("Invalid layer \"{}\"", layer.trim())),
+            };
+            let voxel_type = registry::get()
+                .find(name)
+                .ok_or_else(|| format!("Unknown block {} in layers", name))?;
+            Ok((voxel_type, count))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    if parsed.is_empty() {
+        return Err("A superflat world needs at least one layer".to_string());
+    }
+    total_height(&parsed)?;
+    Ok(parsed)
+}
+
+/// Height of the layers stacked on each other, if it fits the world.
+fn total_height(layers: &[(VoxelType, i32)]) -> Result<i32, String> {
+    layers
+        .iter()
+        .try_fold(0i32, |height, (_, count)| height.checked_add(*count))
+        .ok_or_else(|| format!("The layers are higher than {} voxels", i32::MAX))
+}
+
+impl TerrainPreset {
+    /// A flat world made of `layers` (see `parse_layers`) stacked up from
+    /// y = 0, with nothing below them.
+    pub fn superflat(layers: &str) -> Result<TerrainPreset, String> {
+        let height = total_height(&parse_layers(layers)?)?;
+        TerrainPreset::from_value(serde_json::json!({
+            "height": { "type": "constant", "value": height },
+            "stages": [{ "type": "layers", "layers": layers }],
+        }))
+    }
+}
+
+/// Fills chunks with the layers of a superflat world, replacing whatever the
+/// earlier stages made.
+pub struct LayersStage {
+    layers: Vec<(VoxelType, i32)>,
+}
+
+impl LayersStage {
+    /// Builds the stage from its preset entry, which has the `layers`.
+    pub fn build(
+        entry: &serde_json::Value,
+        _seed: u64,
+    ) -> Result<Box<dyn GenerationStage>, String> {
+        let layers = entry["layers"]
+            .as_str()
+            .ok_or("layers needs to be a layer list")?;
+        Ok(Box::new(LayersStage {
+            layers: parse_layers(layers)?,
+        }))
+    }
+}
+
+impl GenerationStage for LayersStage {
+    fn run(&self, context: &mut StageContext) {
+        let origin = context.index.chunk_origin();
+        *context.chunk = Chunk::gen_layers(origin.y, &self.layers);
+    }
+
+    fn is_ground(&self, _: &Caves, _: &Column, at: Vector3<i32>, _: bool) -> bool {
+        let mut bottom = 0;
+        for (voxel_type, count) in &self.layers {
+            if at.y >= bottom && at.y < bottom + count {
+                return *voxel_type != VoxelType::VOID;
+            }
+            bottom += count;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::gen::WorldGenerator;
+    use crate::world::Voxel;
+
+    #[test]
+    pub fn layer_lists() {
+        let dirt = registry::get().find("dirt").unwrap();
+        assert_eq!(
+            parse_layers("2 dirt, air ,3 dirt").unwrap(),
+            vec![(dirt, 2), (VoxelType::VOID, 1), (dirt, 3)]
+        );
+        assert!(parse_layers("").is_err());
+        assert!(parse_layers("1 dirt,").is_err());
+        assert!(parse_layers("0 dirt").is_err());
+        assert!(parse_layers("3 unobtainium").is_err());
+        assert!(TerrainPreset::superflat("two dirt").is_err());
+        assert!(parse_layers("2147483647 dirt, 1 dirt").is_err());
+        assert!(TerrainPreset::superflat("2147483000 dirt, 2000 air").is_err());
+        assert!(TerrainPreset::superflat("2147483000 dirt, 600 air").is_ok());
+
+        // Layers can reach up into the topmost chunk
+        let dirt = Voxel::new(VoxelType::GROUND);
+        let layers = [(VoxelType::VOID, i32::MAX - 10), (VoxelType::GROUND, 10)];
+        let top = Chunk::gen_layers(i32::MAX - 15, &layers);
+        assert_eq!(*top.voxel(0, 4, 0), Voxel::void());
+        assert_eq!(*top.voxel(0, 5, 0), dirt);
+        assert_eq!(*top.voxel(0, 14, 0), dirt);
+        assert_eq!(*top.voxel(0, 15, 0), Voxel::void());
+    }
+
+    #[test]
+    pub fn superflat_worlds_regenerate_identically() {
+        let preset = TerrainPreset::superflat("14 dirt, 2 air, 3 dirt").unwrap();
+        // Stored in a save and read back
+        let stored = TerrainPreset::from_value(preset.to_value().clone()).unwrap();
+        assert_eq!(stored, preset);
+
+        let generator = WorldGenerator::new(1, stored);
+        let (bottom, spilled) = generator.gen_chunk((4, 0, -2));
+        let (top, _) = generator.gen_chunk((4, 1, -2));
+        let dirt = Voxel::new(VoxelType::GROUND);
+        assert!(spilled.is_empty());
+        assert_eq!(*bottom.voxel(5, 13, 5), dirt);
+        assert_eq!(*bottom.voxel(5, 14, 5), Voxel::void());
+        assert_eq!(*bottom.voxel(5, 15, 5), Voxel::void());
+        assert_eq!(*top.voxel(5, 0, 5), dirt);
+        assert_eq!(*top.voxel(5, 2, 5), dirt);
+        assert_eq!(*top.voxel(5, 3, 5), Voxel::void());
+        assert!(generator.gen_chunk((0, -1, 0)).0.voxels.is_uniform());
+        assert_eq!(
+            generator.gen_chunk((4, 1, -2)).0.voxels.to_vec(),
+            top.voxels.to_vec()
+        );
+    }
+}
@@ -0,0 +1,391 @@
+use noise::{
+    Add, Clamp, Constant, Fbm, Max, Min, MultiFractal, Multiply, NoiseFn, Perlin, RidgedMulti,
+    ScaleBias, ScalePoint, Seedable,
+};
+
+use crate::world::biome::BiomeMap;
+use crate::world::caves::Caves;
+use crate::world::erosion::Erosion;
+use crate::world::gen::sub_seed;
+use crate::world::ores;
+use crate::world::stages::{self, StageRegistry};
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Terrain used when no preset is given, gently rolling hills.
+const BUILTIN_PRESET: &str = r#"{
+    "height": {
+        "type": "scale_bias", "scale": 44, "bias": 40,
+        "source": { "type": "perlin", "layer": "height", "frequency": 0.01 }
+    }
+}"#;
+
+/// A terrain shape described as a graph of noise functions, loaded from JSON
+/// so it can be tuned without recompiling.
+///
+/// The terrain is split into `biomes`, each with a `name`, the `temperature`
+/// and `humidity` it's found at, a `height` node giving the surface height in
+/// voxels at a world position `[x, z]`, `surface` and `subsurface` block names,
+/// `decorations` (`block`, `chance` and a `[min, max]` stack `height`) and
+/// `structures` (`type`, one of `tree`, `boulder` and `ruin`, and `chance`).
+/// The climate comes from the `temperature` and `humidity` nodes in `climate`,
+/// `climate.blend` sets how far apart in climate biomes still blend, see
+/// `biome::BiomeMap`. A preset without biomes only has a `height` node.
+///
+/// `overhangs` (`density`, `range`) and `caves` (`density`, `threshold`,
+/// `floor`) are optional 3D nodes sampled at `[x, y, z]`, see `caves::Caves`.
+/// An `erosion` section runs water over the height map before it's turned
+/// into voxels, see `erosion::Erosion` for its settings.
+/// `stages` lists the steps generating a chunk, see `stages::StageRegistry`.
+/// The `ores` stage gets the ore rules of the block registry written into its
+/// entry, so a saved world keeps its ores when the blocks change.
+///
+/// Every node is an object with a `type`:
+///
+/// - `perlin`, `fbm` and `ridged` noise with a `frequency` (per voxel) and,
+///   for the fractals, `octaves`, `lacunarity` and `persistence`. Their seed is
+///   derived from the world seed and the `layer` name, which defaults to the
+///   node's path in the graph.
+/// - `constant` with a `value`.
+/// - `add`, `multiply`, `min` and `max` over a list of `sources`.
+/// - `scale_bias` (`scale`, `bias`) and `clamp` (`min`, `max`) of a `source`.
+/// - `curve` remapping a `source` through `points`, a list of `[in, out]`
+///   pairs interpolated linearly and held constant past the ends.
+/// - `warp`, sampling a `source` at a position moved by the values of the
+///   `x` and `z` nodes (domain warping).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TerrainPreset {
+    json: serde_json::Value,
+}
+
+impl Default for TerrainPreset {
+    fn default() -> TerrainPreset {
+        TerrainPreset::from_json(BUILTIN_PRESET).unwrap()
+    }
+}
+
+impl TerrainPreset {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TerrainPreset, String> {
+        let mut file = File::open(path.as_ref())
+            .map_err(|e| format!("Failed to open {}: {}", path.as_ref().display(), e))?;
+        let mut buffer = String::new();
+        file.read_to_string(&mut buffer)
+            .map_err(|e| format!("Failed to read {}: {}", path.as_ref().display(), e))?;
+
+        TerrainPreset::from_json(&buffer)
+    }
+
+    pub fn from_json(json: &str) -> Result<TerrainPreset, String> {
+        let json: serde_json::Value =
+            serde_json::from_str(json).map_err(|e| format!("Invalid terrain preset: {}", e))?;
+        TerrainPreset::from_value(json)
+    }
+
+    /// Takes a preset that's already parsed, like the one stored in a save.
+    pub fn from_value(json: serde_json::Value) -> Result<TerrainPreset, String> {
+        TerrainPreset::with_stages(json, stages::get())
+    }
+
+    /// Takes a preset using stages of a registry other than the installed
+    /// one.
+    pub fn with_stages(
+        mut json: serde_json::Value,
+        registry: &StageRegistry,
+    ) -> Result<TerrainPreset, String> {
+        ores::store_rules(&mut json);
+        // Building it once catches mistakes before any chunk is generated
+        BiomeMap::new(&json, 0)?;
+        Caves::new(&json, 0)?;
+        Erosion::new(&json)?;
+        registry.build(&json, 0)?;
+        Ok(TerrainPreset { json })
+    }
+
+    pub fn to_value(&self) -> &serde_json::Value {
+        &self.json
+    }
+
+    /// The biomes of the preset for a world seed.
+    pub fn biomes(&self, seed: u64) -> BiomeMap {
+        BiomeMap::new(&self.json, seed).expect("Terrain preset was validated.")
+    }
+
+    /// The overhangs and caves of the preset for a world seed.
+    pub fn caves(&self, seed: u64) -> Caves {
+        Caves::new(&self.json, seed).expect("Terrain preset was validated.")
+    }
+
+    /// How the height map is eroded, if it is.
+    pub fn erosion(&self) -> Option<Erosion> {
+        Erosion::new(&self.json).expect("Terrain preset was validated.")
+    }
+}
+
+/// A node of a `TerrainPreset`'s noise graph, ready to be sampled.
+pub enum NoiseNode {
+    /// Perlin noise has no frequency of its own, its input is scaled instead.
+    Perlin(ScalePoint<Perlin>),
+    Fbm(Fbm),
+    Ridged(RidgedMulti),
+    Constant(Constant),
+    Add(Vec<NoiseNode>),
+    Multiply(Vec<NoiseNode>),
+    Min(Vec<NoiseNode>),
+    Max(Vec<NoiseNode>),
+    ScaleBias(Box<NoiseNode>, f64, f64),
+    Clamp(Box<NoiseNode>, f64, f64),
+    Curve(Box<NoiseNode>, Vec<(f64, f64)>),
+    Warp(Box<NoiseNode>, Box<NoiseNode>, Box<NoiseNode>),
+}
+
+/// Positions a `NoiseNode` can be sampled at, `[x, z]` for heights and
+/// `[x, y, z]` for densities.
+pub trait NoisePoint: Copy {
+    /// The point moved along x and z, heights and densities are warped alike.
+    fn warped(self, dx: f64, dz: f64) -> Self;
+}
+
+impl NoisePoint for [f64; 2] {
+    fn warped(self, dx: f64, dz: f64) -> Self {
+        [self[0] + dx, self[1] + dz]
+    }
+}
+
+impl NoisePoint for [f64; 3] {
+    fn warped(self, dx: f64, dz: f64) -> Self {
+        [self[0] + dx, self[1], self[2] + dz]
+    }
+}
+
+fn fold<P, F>(sources: &[NoiseNode], point: P, combine: F) -> f64
+where
+    P: NoisePoint,
+    NoiseNode: NoiseFn<P>,
+    F: Fn(&dyn NoiseFn<P>, &dyn NoiseFn<P>) -> f64,
+{
+    let mut value = sources[0].get(point);
+    for source in &sources[1..] {
+        value = combine(&Constant::new(value), source);
+    }
+    value
+}
+
+impl<P> NoiseFn<P> for NoiseNode
+where
+    P: NoisePoint,
+    ScalePoint<Perlin>: NoiseFn<P>,
+    Fbm: NoiseFn<P>,
+    RidgedMulti: NoiseFn<P>,
+{
+    fn get(&self, point: P) -> f64 {
+        match self {
+            NoiseNode::Perlin(perlin) => perlin.get(point),
+            NoiseNode::Fbm(fbm) => fbm.get(point),
+            NoiseNode::Ridged(ridged) => ridged.get(point),
+            NoiseNode::Constant(constant) => constant.get(point),
+            NoiseNode::Add(sources) => fold(sources, point, |a, b| Add::new(a, b).get(point)),
+            NoiseNode::Multiply(sources) => {
+                fold(sources, point, |a, b| Multiply::new(a, b).get(point))
+            }
+            NoiseNode::Min(sources) => fold(sources, point, |a, b| Min::new(a, b).get(point)),
+            NoiseNode::Max(sources) => fold(sources, point, |a, b| Max::new(a, b).get(point)),
+            NoiseNode::ScaleBias(source, scale, bias) => ScaleBias::new(&**source)
+                .set_scale(*scale)
+                .set_bias(*bias)
+                .get(point),
+            NoiseNode::Clamp(source, min, max) => {
+                Clamp::new(&**source).set_bounds(*min, *max).get(point)
+            }
+            // `noise::Curve` is cubic and prints a warning for every value
+            // below its second point, so the curve is interpolated here.
+            NoiseNode::Curve(source, points) => {
+                let value = source.get(point);
+                match points.iter().position(|(input, _)| *input > value) {
+                    Some(0) => points[0].1,
+                    Some(n) => {
+                        let ((x0, y0), (x1, y1)) = (points[n - 1], points[n]);
+                        y0 + (y1 - y0) * (value - x0) / (x1 - x0)
+                    }
+                    None => points[points.len() - 1].1,
+                }
+            }
+            NoiseNode::Warp(source, x, z) => source.get(point.warped(x.get(point), z.get(point))),
+        }
+    }
+}
+
+pub(super) fn build(node: &serde_json::Value, seed: u64, path: &str) -> Result<NoiseNode, String> {
+    let number = |key: &str, default: Option<f64>| -> Result<f64, String> {
+        match (&node[key], default) {
+            (serde_json::Value::Null, Some(default)) => Ok(default),
+            (value, _) => value
+                .as_f64()
+                .ok_or_else(|| format!("{}.{} needs to be a number", path, key)),
+        }
+    };
+    let source = |key: &str| -> Result<Box<NoiseNode>, String> {
+        build(&node[key], seed, &format!("{}.{}", path, key)).map(Box::new)
+    };
+    let sources = || -> Result<Vec<NoiseNode>, String> {
+        let sources = node["sources"]
+            .as_array()
+            .filter(|sources| !sources.is_empty())
+            .ok_or_else(|| format!("{}.sources needs to be a list of nodes", path))?;
+        sources
+            .iter()
+            .enumerate()
+            .map(|(n, source)| build(source, seed, &format!("{}.sources.{}", path, n)))
+            .collect()
+    };
+    let layer_seed = || sub_seed(seed, node["layer"].as_str().unwrap_or(path));
+    let octaves = || -> Result<usize, String> {
+        Ok(number("octaves", Some(Fbm::DEFAULT_OCTAVE_COUNT as f64))?
+            .max(1.0)
+            .min(Fbm::MAX_OCTAVES as f64) as usize)
+    };
+
+    let kind = node["type"]
+        .as_str()
+        .ok_or_else(|| format!("{} needs a type", path))?;
+    Ok(match kind {
+        "perlin" => NoiseNode::Perlin(
+            ScalePoint::new(Perlin::new().set_seed(layer_seed()))
+                .set_scale(number("frequency", Some(1.0))?),
+        ),
+        "fbm" => NoiseNode::Fbm(
+            Fbm::new()
+                .set_seed(layer_seed())
+                .set_frequency(number("frequency", Some(1.0))?)
+                .set_octaves(octaves()?)
+                .set_lacunarity(number("lacunarity", Some(Fbm::DEFAULT_LACUNARITY))?)
+                .set_persistence(number("persistence", Some(Fbm::DEFAULT_PERSISTENCE))?),
+        ),
+        "ridged" => NoiseNode::Ridged(
+            RidgedMulti::new()
+                .set_seed(layer_seed())
+                .set_frequency(number("frequency", Some(1.0))?)
+                .set_octaves(octaves()?)
+                .set_lacunarity(number("lacunarity", Some(RidgedMulti::DEFAULT_LACUNARITY))?)
+                .set_persistence(number(
+                    "persistence",
+                    Some(RidgedMulti::DEFAULT_PERSISTENCE),
+                )?),
+        ),
+        "constant" => NoiseNode::Constant(Constant::new(number("value", None)?)),
+        "add" => NoiseNode::Add(sources()?),
+        "multiply" => NoiseNode::Multiply(sources()?),
+        "min" => NoiseNode::Min(sources()?),
+        "max" => NoiseNode::Max(sources()?),
+        "scale_bias" => NoiseNode::ScaleBias(
+            source("source")?,
+            number("scale", Some(1.0))?,
+            number("bias", Some(0.0))?,
+        ),
+        "clamp" => NoiseNode::Clamp(
+            source("source")?,
+            number("min", Some(-1.0))?,
+            number("max", Some(1.0))?,
+        ),
+        "curve" => {
+            let points = node["points"]
+                .as_array()
+                .ok_or_else(|| format!("{}.points needs to be a list", path))?
+                .iter()
+                .map(|point| match (point[0].as_f64(), point[1].as_f64()) {
+                    (Some(input), Some(output)) => Ok((input, output)),
+                    _ => Err(format!("{}.points needs [in, out] pairs", path)),
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            if points.is_empty() || points.windows(2).any(|w| w[0].0 >= w[1].0) {
+                return Err(format!("{}.points need increasing inputs", path));
+            }
+            NoiseNode::Curve(source("source")?, points)
+        }
+        "warp" => NoiseNode::Warp(source("source")?, source("x")?, source("z")?),
+        _ => return Err(format!("{} has an unknown type {}", path, kind)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn builtin_preset_stays_in_range() {
+        let biomes = TerrainPreset::default().biomes(1);
+        for x in -50..50 {
+            let h = biomes.height_at(x * 7, x * -3);
+            assert!(h >= -4.0 && h <= 84.0, "height {} out of range", h);
+        }
+    }
+
+    #[test]
+    pub fn combinators_and_curves() {
+        let preset = TerrainPreset::from_json(
+            r#"{ "height": {
+                "type": "curve", "points": [[0, 0], [10, 100], [20, 110]],
+                "source": { "type": "add", "sources": [
+                    { "type": "constant", "value": 2 },
+                    { "type": "scale_bias", "scale": 3, "bias": 1,
+                      "source": { "type": "constant", "value": 4 } }
+                ] }
+            } }"#,
+        )
+        .unwrap();
+        // 2 + (3 * 4 + 1) = 15, halfway between 100 and 110
+        assert_eq!(preset.biomes(0).height_at(12, 34), 105.0);
+
+        let clamped = TerrainPreset::from_json(
+            r#"{ "height": { "type": "clamp", "min": 0, "max": 5,
+                "source": { "type": "multiply", "sources": [
+                    { "type": "constant", "value": 4 },
+                    { "type": "constant", "value": 3 }
+                ] } } }"#,
+        )
+        .unwrap();
+        assert_eq!(clamped.biomes(0).height_at(0, 0), 5.0);
+    }
+
+    #[test]
+    pub fn layers_are_seeded_separately() {
+        let preset = TerrainPreset::from_json(
+            r#"{ "height": { "type": "add", "sources": [
+                { "type": "fbm", "frequency": 0.01, "octaves": 3 },
+                { "type": "ridged", "layer": "mountains", "frequency": 0.02 },
+                { "type": "warp", "source": { "type": "perlin", "frequency": 0.05 },
+                  "x": { "type": "fbm", "frequency": 0.1 },
+                  "z": { "type": "fbm", "frequency": 0.1 } }
+            ] } }"#,
+        )
+        .unwrap();
+
+        let sample = |seed| {
+            let biomes = preset.biomes(seed);
+            (0..20)
+                .map(|n| biomes.height_at(n * 13, n * 5))
+                .collect::<Vec<f64>>()
+        };
+        assert_eq!(sample(5), sample(5));
+        assert_ne!(sample(5), sample(6));
+    }
+
+    #[test]
+    pub fn reject_invalid_presets() {
+        assert!(TerrainPreset::from_json("{}").is_err());
+        assert!(TerrainPreset::from_json(r#"{ "height": { "type": "blob" } }"#).is_err());
+        assert!(
+            TerrainPreset::from_json(r#"{ "height": { "type": "add", "sources": [] } }"#).is_err()
+        );
+        assert!(TerrainPreset::from_json(
+            r#"{ "height": { "type": "curve", "points": [[1, 0], [0, 1]],
+                "source": { "type": "constant", "value": 0 } } }"#
+        )
+        .is_err());
+        assert!(TerrainPreset::from_json(
+            r#"{ "height": { "type": "fbm", "frequency": "high" } }"#
+        )
+        .is_err());
+    }
+}
@@ -1,10 +1,16 @@
-#[derive(Copy, Clone, Debug, PartialEq)]
-pub enum VoxelType {
-    VOID,
-    GROUND,
+use crate::world::registry;
+
+/// Numeric id of a block in the `registry::BlockRegistry`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VoxelType(pub u16);
+
+impl VoxelType {
+    pub const VOID: VoxelType = VoxelType(0);
+    /// Plain ground, `dirt` in the default block set.
+    pub const GROUND: VoxelType = VoxelType(1);
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Voxel {
     pub voxel_type: VoxelType,
 }
@@ -16,10 +22,19 @@ impl Voxel {
         }
     }
 
+    pub fn new(voxel_type: VoxelType) -> Voxel {
+        Voxel { voxel_type }
+    }
+
     pub fn is_solid(self) -> bool {
-        match self.voxel_type {
-            VoxelType::VOID => false,
-            _ => true,
-        }
+        registry::get().is_solid(self.voxel_type)
+    }
+
+    pub fn is_transparent(self) -> bool {
+        registry::get().is_transparent(self.voxel_type)
+    }
+
+    pub fn has_collision(self) -> bool {
+        registry::get().has_collision(self.voxel_type)
     }
 }